@@ -14,10 +14,11 @@ use std::io::Write;
 use std::str::FromStr;
 use std::num::ParseIntError;
 use std::env;
+use std::process;
 
 use serde::{Serializer, Deserializer};
 
-use ds::service::{Token, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
 use ds::streamer::pw::PwStreamer;
 
 use time::PreciseTime;
@@ -75,7 +76,8 @@ impl Stat {
         }
     }
     fn print(&self) {
-        info!("now={} conn={} sent={} got={} mean_time={} max_time={}", self.now, self.conn, self.sent, self.got, self.time_total/self.got, self.time_max);
+        let mean_time = self.time_total.checked_div(self.got).unwrap_or(0);
+        info!("now={} conn={} sent={} got={} mean_time={} max_time={}", self.now, self.conn, self.sent, self.got, mean_time, self.time_max);
     }
 }
 
@@ -126,6 +128,7 @@ service_define!(CLIENT_SERVICE : ClientService);
 impl ServiceHandler for ClientService {
     type Packet = ProtocolFrom7001;
     type Streamer = PwStreamer<Self::Packet>;
+    type Session = ();
     fn connected(&self, token : Token) {
         trace!("client_service {:?} connected", token);
         self.stat_set.borrow_mut().conn += 1;
@@ -133,10 +136,10 @@ impl ServiceHandler for ClientService {
             self.send_set(token);
         }
     }
-    fn disconnected(&self, token : Token) {
-        trace!("client_service {:?} disconnected", token);
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        trace!("client_service {:?} disconnected {:?}", token, reason);
     }
-    fn incoming(&self, token : Token, packet : Self::Packet) {
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
         //trace!("client_service {:?} incoming", token);
         match packet {
             ProtocolFrom7001::SetRe(key, ret) => {
@@ -181,7 +184,7 @@ impl ServiceHandler for ClientService {
             }
         }
     }
-    fn outgoing(&self, _token : Token, _packet : &Self::Packet) {
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
     }
 }
 
@@ -191,7 +194,17 @@ fn main() {
     let total : u32 = args[2].parse().unwrap();
     init();
     let client_service = ClientService::new(concur, total);
-    service_start!(CLIENT_SERVICE, client_service, ServiceConfig::from_file("config.toml", "client_service"));
+    let started = ServiceConfig::from_file("config.toml", "client_service").and_then(|config| {
+        service_start!(CLIENT_SERVICE, client_service, config)
+    });
+    if let Err(e) = started {
+        error!("client_service: {}", e);
+        process::exit(1);
+    }
+    service_timer!(CLIENT_SERVICE, 1_000, true, None, |client : &ClientService| {
+        client.stat_set.borrow().print();
+        client.stat_get.borrow().print();
+    });
     run_loop();
 }
 
@@ -13,20 +13,20 @@ extern crate rustc_serialize;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::str::FromStr;
 use std::num::ParseIntError;
-use std::fs::File;
+use std::process;
 use serde::{Serializer, Deserializer};
 
-use ds::service::{Token, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, Balance, init, run_loop, run_workers, load_config};
 use ds::streamer::pw::PwStreamer;
 use ds::streamer::memcached::MemcachedStreamer;
 use ds::streamer::memcached;
 
 use time::PreciseTime;
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
 struct TableConfig {
     prefix : String,
     count : u32,
@@ -86,13 +86,23 @@ service_define!(DB_SERVICE : DbService);
 impl ServiceHandler for FrontService {
     type Packet = ProtocolFrom7001;
     type Streamer = PwStreamer<Self::Packet>;
+    type Session = ();
     fn connected(&self, token : Token) {
         trace!("front_service {:?} connected", token);
     }
-    fn disconnected(&self, token : Token) {
-        trace!("front_service {:?} disconnected", token);
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        trace!("front_service {:?} disconnected {:?}", token, reason);
     }
-    fn incoming(&self, token : Token, packet : Self::Packet) {
+    fn reconfigured(&self, changed : &toml::Table) {
+        match changed.get("table").cloned().and_then(toml::decode::<TableConfig>) {
+            Some(table) => {
+                info!("front_service table now {:?}", table);
+                *self.table.borrow_mut() = table;
+            }
+            None => {}
+        }
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
         match packet {
             ProtocolFrom7001::Set(key, value) => {
                 let mut ongoing = self.ongoing.borrow_mut();
@@ -100,10 +110,17 @@ impl ServiceHandler for FrontService {
                     None => 0,
                     Some(n) => *n + 1,
                 };
-                let keystr = key.to_string(&self.table);
+                let keystr = key.to_string(&*self.table.borrow());
             	trace!("front_service {:?} {:?} receive request set {:?} {}", token, opaque, key, keystr);
                 ongoing.insert(opaque, Ongoing { token : token, roleid : 0, key : key, time : PreciseTime::now() });
-                service_broadcast!(DB_SERVICE, &memcached::protocol::Packet::new_request_set(opaque, keystr, value));
+                let request = memcached::protocol::Packet::new_request_set(opaque, keystr.clone(), value);
+                match service_send_balanced!(DB_SERVICE, &request, Balance::ConsistentHash(keystr.as_bytes())) {
+                    Ok(_) => {}
+                    Err(status) => {
+            	        trace!("front_service {:?} {:?} set not sent to db {:?}", token, opaque, status);
+                        ongoing.remove(&opaque);
+                    }
+                }
             }
             ProtocolFrom7001::Get(roleid, key) => {
                 let mut ongoing = self.ongoing.borrow_mut();
@@ -111,30 +128,38 @@ impl ServiceHandler for FrontService {
                     None => 0,
                     Some(n) => *n + 1,
                 };
-                let keystr = key.to_string(&self.table);
+                let keystr = key.to_string(&*self.table.borrow());
             	trace!("front_service {:?} {:?} receive request get {:?} {}", token, opaque, key, keystr);
                 ongoing.insert(opaque, Ongoing { token : token, roleid : roleid, key : key, time : PreciseTime::now()});
-                service_broadcast!(DB_SERVICE, &memcached::protocol::Packet::new_request_get(opaque, keystr));
+                let request = memcached::protocol::Packet::new_request_get(opaque, keystr.clone());
+                match service_send_balanced!(DB_SERVICE, &request, Balance::ConsistentHash(keystr.as_bytes())) {
+                    Ok(_) => {}
+                    Err(status) => {
+            	        trace!("front_service {:?} {:?} get not sent to db {:?}", token, opaque, status);
+                        ongoing.remove(&opaque);
+                    }
+                }
             }
             _ => {
                 service_shutdown!(FRONT_SERVICE, token);
             }
         }
     }
-    fn outgoing(&self, _token : Token, _packet : &Self::Packet) {
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
     }
 }
 
 impl ServiceHandler for DbService {
     type Packet = memcached::protocol::Packet;
     type Streamer = MemcachedStreamer;
+    type Session = ();
     fn connected(&self, token : Token) {
         trace!("db_service {:?} connected to db", token);
     }
-    fn disconnected(&self, token : Token) {
-        trace!("db_service {:?} dosconnected to db", token);
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        trace!("db_service {:?} dosconnected to db {:?}", token, reason);
     }
-    fn incoming(&self, intoken : Token, packet : Self::Packet) {
+    fn incoming(&self, intoken : Token, _session : &mut Self::Session, packet : Self::Packet) {
         let now = PreciseTime::now();
         match packet.header.opcode {
             memcached::protocol::PROTOCOL_BINARY_CMD_GET => {
@@ -174,25 +199,53 @@ impl ServiceHandler for DbService {
             }
         }
     }
-    fn outgoing(&self, _token : Token, _packet : &Self::Packet) {
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
     }
 }
 
-fn main() {
-    let mut file = File::open("config.toml").unwrap();
-    let mut st = String::new();
-    file.read_to_string(&mut st).unwrap();
-    let mut map = toml::Parser::new(&st).parse().unwrap();
-    let value = map.remove("table").unwrap();
-    let table = toml::decode(value).unwrap();
-    init();
+const CONFIG : &'static str = "config.toml";
+
+fn config() -> toml::Table {
+    match load_config(CONFIG, None) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("cache_server: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// SIGHUP rereads config.toml: the services pick up their sections, the front service its table
+fn start() {
+    let mut config = config();
+    let table : TableConfig = config.remove("table").and_then(toml::decode).unwrap();
     let ongoing = Rc::new(RefCell::new(BTreeMap::new()));
-    let front_service = FrontService { ongoing : ongoing.clone(), table : table };
+    let front_service = FrontService { ongoing : ongoing.clone(), table : RefCell::new(table) };
     let db_service = DbService { ongoing : ongoing.clone() };
-    let front_config = map.remove("front_service").unwrap();
-    let db_config = map.remove("db_service").unwrap();
-    service_start!(FRONT_SERVICE, front_service, ServiceConfig::from_toml(front_config));
-    service_start!(DB_SERVICE, db_service, ServiceConfig::from_toml(db_config));
-    run_loop();
+    let front_config = config.remove("front_service").unwrap();
+    let db_config = config.remove("db_service").unwrap();
+    let started = ServiceConfig::from_toml(front_config).and_then(|config| {
+        service_start!(FRONT_SERVICE, front_service, config)
+    }).and_then(|_| ServiceConfig::from_toml(db_config)).and_then(|config| {
+        service_start!(DB_SERVICE, db_service, config)
+    });
+    if let Err(e) = started {
+        error!("cache_server: {}", e);
+        process::exit(1);
+    }
+    FRONT_SERVICE.with(|s| s.borrow().as_ref().unwrap().watch_section("front_service"));
+    DB_SERVICE.with(|s| s.borrow().as_ref().unwrap().watch_section("db_service"));
 }
 
+fn main() {
+    let workers = config().get("workers").and_then(|w| w.as_integer()).unwrap_or(1) as usize;
+    if workers > 1 {
+        run_workers(workers, move |_| {
+            start();
+        });
+    } else {
+        init();
+        start();
+        run_loop();
+    }
+}
@@ -14,10 +14,11 @@ use std::collections::BTreeMap;
 use std::io::Write;
 use std::str::FromStr;
 use std::num::ParseIntError;
+use std::process;
 
 use serde::{Serializer, Deserializer};
 
-use ds::service::{Token, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
 use ds::streamer::pw::PwStreamer;
 use ds::streamer::memcached::MemcachedStreamer;
 use ds::streamer::memcached;
@@ -64,13 +65,14 @@ service_define!(FRONT_SERVICE : FrontService);
 impl ServiceHandler for FrontService {
     type Packet = ProtocolFrom7001;
     type Streamer = PwStreamer<Self::Packet>;
+    type Session = ();
     fn connected(&self, token : Token) {
         trace!("front_service {:?} connected", token);
     }
-    fn disconnected(&self, token : Token) {
-        trace!("front_service {:?} disconnected", token);
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        trace!("front_service {:?} disconnected {:?}", token, reason);
     }
-    fn incoming(&self, token : Token, packet : Self::Packet) {
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
         match packet {
             ProtocolFrom7001::Set(key, value) => {
             	trace!("front_service {:?} receive request set {:?}", token, key);
@@ -87,14 +89,20 @@ impl ServiceHandler for FrontService {
             }
         }
     }
-    fn outgoing(&self, _token : Token, _packet : &Self::Packet) {
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
     }
 }
 
 fn main() {
     init();
     let front_service = FrontService {};
-    service_start!(FRONT_SERVICE, front_service, ServiceConfig::from_file("config.toml", "front_service"));
+    let started = ServiceConfig::from_file("config.toml", "front_service").and_then(|config| {
+        service_start!(FRONT_SERVICE, front_service, config)
+    });
+    if let Err(e) = started {
+        error!("front_service: {}", e);
+        process::exit(1);
+    }
     run_loop();
 }
 
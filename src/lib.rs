@@ -10,6 +10,14 @@ extern crate serde_json;
 extern crate env_logger;
 extern crate rustc_serialize;
 extern crate toml;
+extern crate net2;
+extern crate libc;
+extern crate rand;
+extern crate time;
+extern crate openssl;
+extern crate openssl_verify;
+#[macro_use]
+extern crate lazy_static;
 
 #[macro_use]
 pub mod service;
@@ -0,0 +1,33 @@
+use std::fmt;
+use std::net::{SocketAddr, IpAddr};
+use std::path::PathBuf;
+
+/// Where a service listens or connects to: `ip:port`, `tls:ip:port` or `unix:/path/to.sock` in the config.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    /// the host as written in the config, the server certificate must be for it
+    Tls(SocketAddr, String),
+    Unix(PathBuf),
+}
+
+impl Addr {
+    /// The peer ip, for the allow/deny lists and per-ip limits, unix sockets have none.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match *self {
+            Addr::Tcp(ref a) => Some(a.ip()),
+            Addr::Tls(ref a, _) => Some(a.ip()),
+            Addr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::Tcp(ref a) => write!(f, "{}", a),
+            Addr::Tls(ref a, _) => write!(f, "tls:{}", a),
+            Addr::Unix(ref p) => write!(f, "unix:{}", p.display()),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::str::FromStr;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// An address block like `10.1.0.0/16` or `fd00::/8`. A bare address is a block of one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cidr {
+    addr : IpAddr,
+    prefix : u32,
+}
+
+fn ip_bytes(ip : &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ref a) => a.octets().to_vec(),
+        IpAddr::V6(ref a) => {
+            let mut bytes = Vec::with_capacity(16);
+            for s in a.segments().iter() {
+                bytes.push((*s >> 8) as u8);
+                bytes.push(*s as u8);
+            }
+            bytes
+        }
+    }
+}
+
+fn is_mapped(a : &Ipv6Addr) -> bool {
+    let s = a.segments();
+    s[0] == 0 && s[1] == 0 && s[2] == 0 && s[3] == 0 && s[4] == 0 && s[5] == 0xffff
+}
+
+/// An IPv4 peer accepted on a dual-stack `[::]` listener shows up as `::ffff:a.b.c.d`, match it as `a.b.c.d`.
+fn unmapped(ip : &IpAddr) -> IpAddr {
+    match *ip {
+        IpAddr::V6(ref a) if is_mapped(a) => IpAddr::V4(a.to_ipv4().unwrap()),
+        _ => *ip,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip : &IpAddr) -> bool {
+        let net = ip_bytes(&self.addr);
+        let other = ip_bytes(&unmapped(ip));
+        if net.len() != other.len() {
+            return false;
+        }
+        let mut bits = self.prefix;
+        for (a, b) in net.iter().zip(other.iter()) {
+            if bits == 0 {
+                break;
+            }
+            let mask = if bits >= 8 { 0xff } else { 0xffu8 << (8 - bits) };
+            if a & mask != b & mask {
+                return false;
+            }
+            bits = bits.saturating_sub(8);
+        }
+        true
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+    fn from_str(s : &str) -> Result<Cidr, ()> {
+        let mut parts = s.splitn(2, '/');
+        let addr = match parts.next().map(|a| IpAddr::from_str(a.trim())) {
+            Some(Ok(addr)) => addr,
+            _ => {
+                return Err(());
+            }
+        };
+        let max = (ip_bytes(&addr).len() * 8) as u32;
+        let prefix = match parts.next() {
+            None => max,
+            Some(p) => {
+                match u32::from_str(p.trim()) {
+                    Ok(p) if p <= max => p,
+                    _ => {
+                        return Err(());
+                    }
+                }
+            }
+        };
+        match addr {
+            // a rule written in the mapped form covers the same IPv4 block
+            IpAddr::V6(ref a) if is_mapped(a) && prefix >= 96 => {
+                Ok(Cidr { addr : unmapped(&addr), prefix : prefix - 96 })
+            }
+            _ => Ok(Cidr { addr : addr, prefix : prefix }),
+        }
+    }
+}
+
+/// Deny wins over allow, an empty allow list lets everyone else in.
+pub fn allowed(ip : &IpAddr, allow : &[Cidr], deny : &[Cidr]) -> bool {
+    if deny.iter().any(|c| c.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|c| c.contains(ip))
+}
@@ -1,15 +1,136 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::Path;
 use std::ops::Deref;
+use std::u32;
 use std::io::Read;
 
 use toml;
+use rustc_serialize::Decodable;
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+use super::error::ServiceError;
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Default, Debug)]
 pub struct ServiceConfig {
     pub name : String,
+    /// "ip:port", "host:port", "unix:/path/to.sock", or "tls:host:port" for TLS over TCP
     pub listen : Vec<String>,
     pub connect : Vec<String>,
+    pub reconnect : Option<ReconnectConfig>,
+    /// `reconnect` for single `connect` addresses, keyed as written there
+    pub reconnect_to : Option<HashMap<String, ReconnectConfig>>,
+    pub relisten : Option<ReconnectConfig>,
+    pub watermark : Option<WatermarkConfig>,
+    pub limits : Option<LimitsConfig>,
+    pub allow : Option<Vec<String>>,
+    pub deny : Option<Vec<String>>,
+    pub max_skips : Option<u32>,
+    pub linger : Option<u64>,
+    pub tls : Option<TlsConfig>,
+    pub socket : Option<SocketConfig>,
+}
+
+/// Options of the TCP sockets the service listens on, accepts and connects, the `[service.socket]` section.
+#[derive(RustcEncodable, RustcDecodable, Clone, Default, Debug)]
+pub struct SocketConfig {
+    pub nodelay : Option<bool>,
+    pub keepalive : Option<u32>,
+    pub keepalive_interval : Option<u32>,
+    pub keepalive_count : Option<u32>,
+    pub send_buffer : Option<usize>,
+    pub recv_buffer : Option<usize>,
+    pub backlog : Option<i32>,
+    pub reuseaddr : Option<bool>,
+}
+
+/// Certificates for the "tls:" endpoints, the `[service.tls]` section. Paths are to PEM files.
+#[derive(RustcEncodable, RustcDecodable, Clone, Default, Debug)]
+pub struct TlsConfig {
+    pub cert : Option<String>,
+    pub key : Option<String>,
+    pub ca : Option<String>,
+    pub verify_peer : Option<bool>,
+    pub insecure : Option<bool>,
+}
+
+/// Caps on accepted connections, the `[service.limits]` section. Outbound connections are not counted.
+#[derive(RustcEncodable, RustcDecodable, Clone, Default, Debug)]
+pub struct LimitsConfig {
+    pub max_connections : Option<usize>,
+    pub max_per_ip : Option<usize>,
+    pub max_accepts_per_tick : Option<usize>,
+}
+
+/// Per-connection write buffer limits in bytes, the `[service.watermark]` section.
+/// `overflow` is what a write does once `high` bytes are pending: "block", "drop" or "close".
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct WatermarkConfig {
+    pub high : usize,
+    pub low : usize,
+    pub overflow : String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Overflow {
+    Block,
+    Drop,
+    Close,
+}
+
+impl WatermarkConfig {
+    pub fn overflow(&self) -> Option<Overflow> {
+        match &*self.overflow {
+            "block" => Some(Overflow::Block),
+            "drop" => Some(Overflow::Drop),
+            "close" => Some(Overflow::Close),
+            _ => None,
+        }
+    }
+}
+
+/// How outgoing connections are retried, the `[service.reconnect]` section.
+/// Delays are in ms, `jitter` is the fraction of the delay added or taken away at random.
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, Default)]
+pub struct ReconnectConfig {
+    pub initial_delay : Option<u64>,
+    pub multiplier : Option<f64>,
+    pub max_delay : Option<u64>,
+    pub jitter : Option<f64>,
+    pub max_attempts : Option<u32>,
+}
+
+impl ReconnectConfig {
+    pub fn delay(&self, attempt : u32, random : f64) -> u64 {
+        let multiplier = self.multiplier.unwrap_or(1.0);
+        let max_delay = self.max_delay.map(|d| d as f64);
+        let mut delay = self.initial_delay.unwrap_or(5_000) as f64;
+        for _ in 1..attempt {
+            delay *= multiplier;
+            match max_delay {
+                Some(max) if delay >= max => break,
+                _ => {}
+            }
+        }
+        match max_delay {
+            Some(max) if delay > max => delay = max,
+            _ => {}
+        }
+        delay *= 1.0 + self.jitter.unwrap_or(0.0) * (random * 2.0 - 1.0);
+        if delay < 0.0 {
+            0
+        } else if delay >= u32::MAX as f64 {
+            // an uncapped backoff, keep the timer arithmetic from overflowing
+            u32::MAX as u64
+        } else {
+            delay as u64
+        }
+    }
+    pub fn gave_up(&self, attempts : u32) -> bool {
+        match self.max_attempts {
+            None => false,
+            Some(max) => attempts >= max,
+        }
+    }
 }
 
 impl ServiceConfig {
@@ -20,6 +141,17 @@ impl ServiceConfig {
             name : name.to_string(),
             listen : vec![addr.to_string()],
             connect : vec![],
+            reconnect : None,
+            reconnect_to : None,
+            relisten : None,
+            watermark : None,
+            limits : None,
+            allow : None,
+            deny : None,
+            max_skips : None,
+            linger : None,
+            tls : None,
+            socket : None,
         }
     }
     pub fn client<A,B>(name : A, addr : B) -> Self
@@ -29,19 +161,48 @@ impl ServiceConfig {
             name : name.to_string(),
             listen : vec![],
             connect : vec![addr.to_string()],
+            reconnect : None,
+            reconnect_to : None,
+            relisten : None,
+            watermark : None,
+            limits : None,
+            allow : None,
+            deny : None,
+            max_skips : None,
+            linger : None,
+            tls : None,
+            socket : None,
         }
     }
-    pub fn from_toml(value : toml::Value) -> Self {
-        toml::decode(value).unwrap()
+    pub fn from_toml(value : toml::Value) -> Result<Self, ServiceError> {
+        let mut decoder = toml::Decoder::new(value);
+        let config = try!(ServiceConfig::decode(&mut decoder));
+        Ok(config)
     }
-    pub fn from_file<P, A>(path : P, name : A) -> Self
+    pub fn from_file<P, A>(path : P, name : A) -> Result<Self, ServiceError>
         where P : AsRef<Path>, A : Deref<Target=str>
     {
-        let mut file = File::open(path).unwrap();
-        let mut st = String::new();
-        file.read_to_string(&mut st).unwrap();
-        let mut map = toml::Parser::new(&st).parse().unwrap();
-        let value = map.remove(&*name).unwrap();
-        Self::from_toml(value)
+        let mut map = try!(parse_file(path));
+        match map.remove(&*name) {
+            Some(value) => Self::from_toml(value),
+            None => Err(ServiceError::MissingSection(name.to_string())),
+        }
+    }
+}
+
+pub fn parse_file<P : AsRef<Path>>(path : P) -> Result<toml::Table, ServiceError> {
+    let mut file = try!(File::open(path));
+    let mut st = String::new();
+    try!(file.read_to_string(&mut st));
+    let mut parser = toml::Parser::new(&st);
+    match parser.parse() {
+        Some(map) => Ok(map),
+        None => {
+            let errors : Vec<String> = parser.errors.iter().map(|e| {
+                let (line, col) = parser.to_linecol(e.lo);
+                format!("{}:{} {}", line + 1, col + 1, e.desc)
+            }).collect();
+            Err(ServiceError::ParseError(errors.join(", ")))
+        }
     }
 }
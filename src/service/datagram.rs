@@ -0,0 +1,374 @@
+use std::rc::{Rc};
+use std::cell::{RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use mio::{Token, Evented, EventSet};
+use mio::udp::UdpSocket;
+
+use super::looper::{LOOPER, EventHandler, Eventer, ShutdownHandler};
+use super::service::{ServiceStreamer, WriteStatus, resolve};
+use super::stream::ErrorKind;
+use super::config::ServiceConfig;
+use super::error::ServiceError;
+use super::addr::Addr;
+use super::sim;
+
+/// Largest datagram read, anything longer is truncated by the kernel and fails to decode.
+const MAX_DATAGRAM_SIZE : usize = 65536;
+/// Datagrams waiting for a socket to become writable, further sends are dropped.
+const MAX_PENDING : usize = 1024;
+
+/// Handler of a datagram service, every datagram carries exactly one packet.
+pub trait DatagramHandler {
+    type Packet;
+    type Streamer : ServiceStreamer<Packet=Self::Packet>;
+    fn incoming(&self, peer : SocketAddr, packet : Self::Packet);
+    /// A datagram from `peer` failed to decode, or a packet to it failed to encode or send.
+    /// The datagram is dropped, there is no connection to close.
+    fn on_error(&self, _peer : SocketAddr, _kind : ErrorKind) {
+    }
+}
+
+pub struct Socket {
+    token : Token,
+    registered : EventSet,
+    interest : EventSet,
+    pub addr : SocketAddr,
+    socket : UdpSocket,
+    pending : VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+impl Socket {
+    fn new(token : Token, addr : SocketAddr, socket : UdpSocket) -> Self {
+        trace!("datagram bind {:?} {}", token, addr);
+        Socket {
+            token : token,
+            registered : EventSet::none(),
+            interest : EventSet::all(),
+            addr : addr,
+            socket : socket,
+            pending : VecDeque::new(),
+        }
+    }
+    fn shutdown(&mut self) {
+        if self.interest == EventSet::none() {
+            return;
+        }
+        trace!("datagram shutdown {:?}", self.token);
+        self.interest = EventSet::none();
+        LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().reregister(self.token);
+        });
+    }
+    fn send_to(&mut self, buf : Vec<u8>, peer : SocketAddr) -> io::Result<WriteStatus> {
+        if !self.pending.is_empty() {
+            return Ok(self.queue(buf, peer));
+        }
+        match try!(self.socket.send_to(&buf, &peer)) {
+            Some(_) => Ok(WriteStatus::Written),
+            None => Ok(self.queue(buf, peer)),
+        }
+    }
+    fn queue(&mut self, buf : Vec<u8>, peer : SocketAddr) -> WriteStatus {
+        if self.pending.len() >= MAX_PENDING {
+            return WriteStatus::Dropped;
+        }
+        self.pending.push_back((buf, peer));
+        WriteStatus::Written
+    }
+    /// Sends what was queued while the socket was not writable, returns the peers it failed for.
+    fn flush(&mut self) -> Vec<(SocketAddr, io::Error)> {
+        let mut failed = Vec::new();
+        loop {
+            let r = match self.pending.front() {
+                None => {
+                    break;
+                }
+                Some(&(ref buf, ref peer)) => self.socket.send_to(buf, peer),
+            };
+            match r {
+                Ok(None) => {
+                    break;
+                }
+                Ok(Some(_)) => {
+                    self.pending.pop_front();
+                }
+                Err(e) => {
+                    let (_, peer) = self.pending.pop_front().unwrap();
+                    failed.push((peer, e));
+                }
+            }
+        }
+        failed
+    }
+}
+
+impl Eventer for Socket {
+    fn registered(&self) -> EventSet {
+        self.registered
+    }
+    fn set_registered(&mut self, es : EventSet) {
+        self.registered = es;
+    }
+    fn interest(&self) -> EventSet {
+        self.interest
+    }
+    fn evented(&self) -> Option<&Evented> {
+        Some(&self.socket)
+    }
+}
+
+pub struct DatagramBody {
+    name : String,
+    sockets : HashMap<Token, Rc<RefCell<Socket>>>,
+}
+
+impl DatagramBody {
+    fn new() -> Self {
+        DatagramBody {
+            name : String::new(),
+            sockets : HashMap::new(),
+        }
+    }
+}
+
+/// A service over UDP sockets, driven by the same looper as the stream services.
+pub struct DatagramRef<H : DatagramHandler + 'static> {
+    service : Rc<RefCell<DatagramBody>>,
+    handler : Rc<RefCell<H>>,
+}
+
+impl<H: DatagramHandler + 'static> Clone for DatagramRef<H> {
+    fn clone(&self) -> Self {
+        DatagramRef {
+            service : self.service.clone(),
+            handler : self.handler.clone(),
+        }
+    }
+}
+
+impl<H: DatagramHandler + 'static> DatagramRef<H> {
+    pub fn new(h : H) -> DatagramRef<H> {
+        DatagramRef {
+            service : Rc::new(RefCell::new(DatagramBody::new())),
+            handler : Rc::new(RefCell::new(h)),
+        }
+    }
+    /// Binds a socket for each `listen` address of the config, `connect` is not used.
+    /// A service that only sends can bind "0.0.0.0:0".
+    pub fn start(&self, config : ServiceConfig) -> Result<(), ServiceError> {
+        let mut on_addrs = Vec::new();
+        for on in config.listen.iter() {
+            match try!(resolve(&config.name, on)) {
+                Addr::Tcp(a) => on_addrs.push(a),
+                _ => {
+                    return Err(ServiceError::ConfigError(config.name.clone(), format!("datagram service can not bind {}", on)));
+                }
+            }
+        }
+        self.service.borrow_mut().name = config.name;
+        LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().register_shutdown(Rc::new(RefCell::new(self.clone())))
+        });
+        for addr in on_addrs {
+            try!(self.bind(addr));
+        }
+        Ok(())
+    }
+    pub fn exit(&self) {
+        let service = self.service.borrow();
+        for socket in service.sockets.values() {
+            socket.borrow_mut().shutdown();
+        }
+    }
+    /// Encodes `packet` into one datagram to `peer`, sent from the first bound socket.
+    pub fn send_to(&self, peer : SocketAddr, packet : &H::Packet) -> WriteStatus {
+        let socket = {
+            let service = self.service.borrow();
+            match service.sockets.keys().min() {
+                None => {
+                    trace!("datagram send_to no socket {}", peer);
+                    return WriteStatus::Closed;
+                }
+                Some(token) => service.sockets.get(token).unwrap().clone(),
+            }
+        };
+        let mut buf = Vec::new();
+        match H::Streamer::write_packet(packet, &mut buf) {
+            Ok(()) => {
+            }
+            Err(e) => {
+                trace!("datagram encode err {} {:?}", peer, e);
+                self.on_error(peer, ErrorKind::Encode);
+                return WriteStatus::Dropped;
+            }
+        }
+        let r = socket.borrow_mut().send_to(buf, peer);
+        match r {
+            Ok(status) => status,
+            Err(e) => {
+                trace!("datagram send_to err {} {:?}", peer, e);
+                self.on_error(peer, ErrorKind::Io(e.kind()));
+                WriteStatus::Dropped
+            }
+        }
+    }
+    /// The addresses the sockets are bound to, with the ports picked for ":0".
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let service = self.service.borrow();
+        service.sockets.values().map(|s| {
+            let s = s.borrow();
+            s.socket.local_addr().unwrap_or(s.addr)
+        }).collect()
+    }
+    fn on_error(&self, peer : SocketAddr, kind : ErrorKind) {
+        trace!("datagram handler on_error begin {} {:?}", peer, kind);
+        self.handler.borrow().on_error(peer, kind);
+        trace!("datagram handler on_error end {}", peer);
+    }
+    fn bind(&self, on : SocketAddr) -> Result<(), ServiceError> {
+        let socket = if sim::active() {
+            Err(io::Error::new(io::ErrorKind::Other, "datagrams are not simulated"))
+        } else {
+            UdpSocket::bound(&on)
+        };
+        let socket = match socket {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(ServiceError::BindError(self.service.borrow().name.clone(), Addr::Tcp(on), e));
+            }
+        };
+        let c : DatagramRef<H> = self.clone();
+        let token = LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().register(Rc::new(RefCell::new(c)))
+        });
+        self.service.borrow_mut().sockets.insert(token, Rc::new(RefCell::new(Socket::new(token, on, socket))));
+        Ok(())
+    }
+    fn on_ready_socket(&self, token : Token, es : EventSet) -> bool {
+        let socket = match self.service.borrow().sockets.get(&token) {
+            None => {
+                return false;
+            }
+            Some(s) => s.clone(),
+        };
+        if es.is_writable() {
+            let failed = socket.borrow_mut().flush();
+            for (peer, e) in failed {
+                trace!("datagram send_to err {} {:?}", peer, e);
+                self.on_error(peer, ErrorKind::Io(e.kind()));
+            }
+        }
+        if es.is_readable() {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                // the socket is edge triggered, read until it runs dry
+                let r = socket.borrow().socket.recv_from(&mut buf);
+                let (len, peer) = match r {
+                    Ok(Some(r)) => r,
+                    Ok(None) => {
+                        break;
+                    }
+                    Err(e) => {
+                        trace!("datagram recv_from err {:?} {:?}", token, e);
+                        break;
+                    }
+                };
+                let packet = {
+                    let mut reader = &buf[..len];
+                    H::Streamer::read_packet(&mut reader)
+                };
+                match packet {
+                    Ok(Some(p)) => {
+                        trace!("datagram handler incoming begin {}", peer);
+                        self.handler.borrow().incoming(peer, p);
+                        trace!("datagram handler incoming end {}", peer);
+                    }
+                    Ok(None) => {
+                        trace!("datagram short {:?} {} {}", token, peer, len);
+                        self.on_error(peer, ErrorKind::Decode);
+                    }
+                    Err(e) => {
+                        trace!("datagram decode err {:?} {} {:?}", token, peer, e);
+                        self.on_error(peer, ErrorKind::Decode);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl<H: DatagramHandler + 'static> EventHandler for DatagramRef<H> {
+    fn get_eventer(&mut self, token : Token) -> Option<Rc<RefCell<Eventer>>> {
+        let service = self.service.borrow();
+        match service.sockets.get(&token) {
+            Some(s) => {
+                Some(s.clone())
+            }
+            None => {
+                None
+            }
+        }
+    }
+    fn on_ready(&mut self, token : Token, es : EventSet) {
+        let ok = self.on_ready_socket(token, es);
+        if !ok {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().reregister(token);
+            });
+        }
+    }
+    fn on_close(&mut self, token : Token) {
+        let r = self.service.borrow_mut().sockets.remove(&token);
+        match r {
+            None => {
+            }
+            Some(s) => {
+                info!("Service {} closed datagram socket {}", self.service.borrow().name, s.borrow().addr);
+            }
+        }
+    }
+}
+
+impl<H: DatagramHandler + 'static> ShutdownHandler for DatagramRef<H> {
+    fn on_shutdown(&mut self) {
+        info!("Service {} shutting down", self.service.borrow().name);
+        self.exit();
+    }
+    fn on_deadline(&mut self) {
+    }
+}
+
+#[macro_export]
+macro_rules! datagram_define {
+    ($n:ident : $t:ty) => {
+        thread_local!(static $n : ::std::cell::RefCell<Option<DatagramRef<$t>>> = ::std::cell::RefCell::new(None));
+    };
+    (pub $n:ident : $t:ty) => {
+        thread_local!(pub static $n : ::std::cell::RefCell<Option<DatagramRef<$t>>> = ::std::cell::RefCell::new(None));
+    };
+}
+#[macro_export]
+macro_rules! datagram_start {
+    ($n:ident, $h:expr, $c:expr) => {
+        $n.with(move |s| {
+            assert!(s.borrow().is_none());
+            *s.borrow_mut() = Some(DatagramRef::new($h));
+            s.borrow_mut().as_mut().unwrap().start($c)
+        })
+    }
+}
+#[macro_export]
+macro_rules! datagram_exit {
+    ($n:ident) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().exit())
+    }
+}
+#[macro_export]
+macro_rules! datagram_send_to {
+    ($n:ident, $a:expr, $p:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().send_to($a, $p))
+    }
+}
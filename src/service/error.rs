@@ -0,0 +1,59 @@
+use std::io;
+use std::error;
+use std::fmt;
+use toml;
+
+use super::addr::Addr;
+
+/// Why a service could not be configured or started.
+/// The variants about addresses carry the service name, so a binary can tell which one failed.
+#[derive(Debug)]
+pub enum ServiceError {
+    IoError(io::Error),
+    ParseError(String),
+    DecodeError(toml::DecodeError),
+    MissingSection(String),
+    ResolveError(String, String, io::Error),
+    BindError(String, Addr, io::Error),
+    ConfigError(String, String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServiceError::IoError(ref e) => write!(f, "config read error: {}", e),
+            ServiceError::ParseError(ref e) => write!(f, "config parse error: {}", e),
+            ServiceError::DecodeError(ref e) => write!(f, "config decode error: {}", e),
+            ServiceError::MissingSection(ref name) => write!(f, "config has no section [{}]", name),
+            ServiceError::ResolveError(ref name, ref addr, ref e) => write!(f, "service {} can not resolve {}: {}", name, addr, e),
+            ServiceError::BindError(ref name, ref addr, ref e) => write!(f, "service {} can not listen on {}: {}", name, addr, e),
+            ServiceError::ConfigError(ref name, ref e) => write!(f, "service {} config error: {}", name, e),
+        }
+    }
+}
+
+impl error::Error for ServiceError {
+    fn description(&self) -> &str {
+        match *self {
+            ServiceError::IoError(_) => "config read error",
+            ServiceError::ParseError(_) => "config parse error",
+            ServiceError::DecodeError(_) => "config decode error",
+            ServiceError::MissingSection(_) => "config section missing",
+            ServiceError::ResolveError(..) => "address resolve error",
+            ServiceError::BindError(..) => "listen bind error",
+            ServiceError::ConfigError(..) => "invalid config",
+        }
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(e : io::Error) -> Self {
+        ServiceError::IoError(e)
+    }
+}
+
+impl From<toml::DecodeError> for ServiceError {
+    fn from(e : toml::DecodeError) -> Self {
+        ServiceError::DecodeError(e)
+    }
+}
@@ -0,0 +1,67 @@
+use mio::Token;
+
+/// What a filter makes of a packet.
+pub enum Filtered<P> {
+    /// hand the packet on as it is
+    Pass,
+    /// hand this packet on instead
+    Replace(P),
+    /// discard the packet, the filters after this one and the handler never see it
+    Drop,
+    /// discard an incoming packet and write this one back to the connection instead.
+    /// Outgoing filters can not reply, the packet is dropped.
+    Reply(P),
+}
+
+/// One link of the chain a service runs its packets through, in the order the filters were added.
+/// Incoming packets pass it before `ServiceHandler::incoming`, outgoing ones before they are encoded.
+/// Writes to the service from inside its filters are dropped, incoming packets are answered with `Filtered::Reply`.
+pub trait PacketFilter<P> {
+    fn incoming(&mut self, _token : Token, _packet : &P) -> Filtered<P> {
+        Filtered::Pass
+    }
+    fn outgoing(&mut self, _token : Token, _packet : &P) -> Filtered<P> {
+        Filtered::Pass
+    }
+    /// The connection is gone, per-connection state can be let go.
+    fn closed(&mut self, _token : Token) {
+    }
+}
+
+/// Runs `packet` through `filters`, the result says what the chain as a whole did to it.
+pub fn run<P>(filters : &mut [Box<PacketFilter<P>>], token : Token, packet : &P, incoming : bool) -> Filtered<P> {
+    let mut replaced : Option<P> = None;
+    for f in filters.iter_mut() {
+        let verdict = {
+            let current = match replaced {
+                Some(ref p) => p,
+                None => packet,
+            };
+            if incoming {
+                f.incoming(token, current)
+            } else {
+                f.outgoing(token, current)
+            }
+        };
+        match verdict {
+            Filtered::Pass => {}
+            Filtered::Replace(p) => {
+                replaced = Some(p);
+            }
+            Filtered::Drop => {
+                return Filtered::Drop;
+            }
+            Filtered::Reply(p) => {
+                if !incoming {
+                    error!("filter replied to an outgoing packet on {:?}, dropping it", token);
+                    return Filtered::Drop;
+                }
+                return Filtered::Reply(p);
+            }
+        }
+    }
+    match replaced {
+        Some(p) => Filtered::Replace(p),
+        None => Filtered::Pass,
+    }
+}
@@ -1,26 +1,185 @@
+use std::io;
+use std::io::Read;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
 use mio::{Token, Evented, EventSet};
 use mio::tcp::TcpListener;
+use mio::unix::{UnixListener, UnixStream};
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use openssl::ssl::SslContext;
 
-use super::looper::{Eventer, LOOPER};
+use super::looper::{Eventer, LOOPER, worker_id};
+use super::stream::Transport;
+use super::sim;
+use super::sim::SimListener;
+use super::addr::Addr;
+use super::tls::TlsStream;
+use super::config::SocketConfig;
+use super::socket;
+
+const LISTEN_BACKLOG : i32 = 1024;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, Addr),
+    Tls(TcpListener, Rc<SslContext>),
+    Sim(SimListener),
+}
+
+impl Listener {
+    pub fn bind(addr : &Addr, tls : Option<Rc<SslContext>>, options : &SocketConfig) -> io::Result<Listener> {
+        match *addr {
+            Addr::Tcp(ref a) => {
+                if sim::active() {
+                    SimListener::bind(a).map(Listener::Sim)
+                } else {
+                    bind(a, options).map(Listener::Tcp)
+                }
+            }
+            Addr::Tls(ref a, _) => {
+                if sim::active() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "tls is not simulated"));
+                }
+                match tls {
+                    Some(ctx) => bind(a, options).map(|l| Listener::Tls(l, ctx)),
+                    None => Err(io::Error::new(io::ErrorKind::Other, "no tls config")),
+                }
+            }
+            Addr::Unix(ref p) => {
+                if sim::active() {
+                    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not simulated"))
+                } else {
+                    bind_unix(p).map(|l| Listener::Unix(l, addr.clone()))
+                }
+            }
+        }
+    }
+    pub fn accept(&self) -> io::Result<Option<(Transport, Addr)>> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|r| r.map(|(s, peer)| (Transport::Tcp(s), Addr::Tcp(peer)))),
+            Listener::Unix(ref l, ref addr) => l.accept().map(|r| r.map(|s| (Transport::Unix(s), addr.clone()))),
+            Listener::Tls(ref l, ref ctx) => {
+                loop {
+                    let (s, peer) = match try!(l.accept()) {
+                        Some(r) => r,
+                        None => {
+                            return Ok(None);
+                        }
+                    };
+                    // a socket the handshake can not even start on is dropped, not the listener
+                    match TlsStream::accept(ctx, s) {
+                        Ok(s) => {
+                            return Ok(Some((Transport::Tls(s), Addr::Tls(peer, peer.ip().to_string()))));
+                        }
+                        Err(e) => {
+                            info!("tls accept from {} err {:?}", peer, e);
+                        }
+                    }
+                }
+            }
+            Listener::Sim(ref l) => l.accept().map(|r| r.map(|(s, peer)| (Transport::Sim(s), Addr::Tcp(peer)))),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        match *self {
+            Listener::Unix(_, Addr::Unix(ref path)) => {
+                // nothing answers on the path any more, do not leave it to the next bind
+                match fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        info!("unlink {} err {:?}", path.display(), e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Whether a socket of this host is bound to `path`, as far as /proc/net/unix tells.
+fn unix_bound(path : &Path) -> bool {
+    let mut table = String::new();
+    match fs::File::open("/proc/net/unix").and_then(|mut f| f.read_to_string(&mut table)) {
+        Ok(_) => {}
+        Err(_) => return false,
+    }
+    let path = path.to_string_lossy();
+    table.lines().skip(1).any(|line| line.split_whitespace().nth(7) == Some(&path[..]))
+}
+
+/// A socket file nothing accepts on is removed before the bind. A path bound in /proc/net/unix is in use,
+/// else a connect probe decides and only a refused one counts as stale.
+fn bind_unix(path : &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) => {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", path.display())));
+            }
+            if unix_bound(path) {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+            }
+            match UnixStream::connect(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    try!(fs::remove_file(path));
+                }
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+                }
+            }
+        }
+        Err(_) => {
+        }
+    }
+    UnixListener::bind(path)
+}
+
+fn bind(addr : &SocketAddr, options : &SocketConfig) -> io::Result<TcpListener> {
+    let builder = try!(match *addr {
+        SocketAddr::V4(..) => TcpBuilder::new_v4(),
+        SocketAddr::V6(..) => TcpBuilder::new_v6(),
+    });
+    match options.reuseaddr {
+        Some(on) => try!(builder.reuse_address(on)),
+        None => {}
+    }
+    // before listen, so the window scale offered to peers fits the receive buffer
+    try!(socket::set_buffers(builder.as_raw_fd(), options));
+    if worker_id().is_some() {
+        // every worker binds the same address, the kernel balances accepts between them
+        try!(builder.reuse_port(true));
+    }
+    try!(builder.bind(addr));
+    let listener = try!(builder.listen(options.backlog.unwrap_or(LISTEN_BACKLOG)));
+    TcpListener::from_listener(listener, addr)
+}
 
 pub struct Listen {
     token : Token,
     registered : EventSet,
     interest : EventSet,
-    pub addr : SocketAddr,
-    pub listener : TcpListener,
+    pub addr : Addr,
+    pub listener : Listener,
+    pub relisten : bool,
 }
 
 impl Listen {
-    pub fn new(token : Token, addr : SocketAddr) -> Self {
+    pub fn new(token : Token, addr : Addr, listener : Listener) -> Self {
         trace!("listen bind {:?} {}", token, addr);
         Listen {
             token : token,
             registered : EventSet::none(),
             interest : EventSet::all(),
             addr : addr,
-            listener : TcpListener::bind(&addr).unwrap(),
+            listener : listener,
+            relisten : true,
         }
     }
     pub fn shutdown(&mut self) {
@@ -45,7 +204,20 @@ impl Eventer for Listen {
     fn interest(&self) -> EventSet {
         self.interest
     }
-    fn evented(&self) -> &Evented {
-        &self.listener
+    fn evented(&self) -> Option<&Evented> {
+        match self.listener {
+            Listener::Tcp(ref l) => Some(l),
+            Listener::Unix(ref l, _) => Some(l),
+            Listener::Tls(ref l, _) => Some(l),
+            Listener::Sim(_) => None,
+        }
+    }
+    fn sim_id(&self) -> Option<usize> {
+        match self.listener {
+            Listener::Tcp(_) => None,
+            Listener::Unix(..) => None,
+            Listener::Tls(..) => None,
+            Listener::Sim(ref l) => Some(l.id()),
+        }
     }
 }
@@ -1,17 +1,31 @@
 use std::collections::HashMap;
-use std::cell::RefCell;
+use std::cell::{RefCell, Cell};
 use std::rc::Rc;
 use std::mem::swap;
-use mio::{Handler, EventLoop, Token, EventSet, PollOpt, Evented, Timeout};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::any::Any;
+use std::marker::PhantomData;
+use mio::{Handler, EventLoop, Token, EventSet, PollOpt, Evented, Timeout, Sender, NotifyError};
+use mio::unix::EventedFd;
 use env_logger;
 
+use super::signal;
+
 thread_local!(pub static LOOPER: RefCell<Option<Looper>> = RefCell::new(None));
+thread_local!(static WORKER: Cell<Option<usize>> = Cell::new(None));
 
 pub trait Eventer {
     fn registered(&self) -> EventSet;
     fn set_registered(&mut self, es : EventSet);
     fn interest(&self) -> EventSet;
-    fn evented(&self) -> &Evented;
+    /// The mio handle to poll, none for simulated transports.
+    fn evented(&self) -> Option<&Evented>;
+    /// The simulated endpoint behind this eventer, if any.
+    fn sim_id(&self) -> Option<usize> {
+        None
+    }
 }
 
 /*
@@ -35,19 +49,123 @@ pub trait TimeHandler {
     fn on_timer(&mut self, tt : TimerToken);
 }
 
+pub trait ShutdownHandler {
+    /// Stop taking new work and start flushing.
+    fn on_shutdown(&mut self);
+    /// The drain deadline passed, drop whatever is left.
+    fn on_deadline(&mut self);
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT : u64 = 5_000;
+// never handed out by new_token, which wraps before it
+const SIGNAL_TOKEN : Token = Token(::std::usize::MAX);
+
+/// Work posted into a looper from another thread, run on the loop thread.
+pub trait LoopMessage : Send {
+    fn deliver(self : Box<Self>);
+}
+
+impl<F> LoopMessage for F
+    where F : FnOnce() + Send
+{
+    fn deliver(self : Box<Self>) {
+        let f = *self;
+        f()
+    }
+}
+
+pub type SendError = NotifyError<Box<LoopMessage>>;
+
+/// A thread-safe handle to a looper, see `sender()`.
+#[derive(Clone)]
+pub struct LoopSender {
+    sender : Sender<Box<LoopMessage>>,
+}
+
+impl LoopSender {
+    /// Run `f` on the loop thread. It may use the thread-local services there, e.g. `service_write!`.
+    pub fn post<F>(&self, f : F) -> Result<(), SendError>
+        where F : FnOnce() + Send + 'static
+    {
+        self.sender.send(Box::new(f))
+    }
+}
+
+/// A thread-safe handle delivering typed messages to a receiver created by `receiver()`.
+/// The receiver goes away with the last clone of its sender, or with the service that made it.
+pub struct MessageSender<M : Send + 'static> {
+    inner : Arc<SenderInner>,
+    p : PhantomData<fn(M)>,
+}
+
+struct SenderInner {
+    id : usize,
+    sender : LoopSender,
+    closed : Arc<AtomicBool>,
+}
+
+impl Drop for SenderInner {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.sender.post(move || remove_receiver(id)).ok();
+    }
+}
+
+impl<M : Send + 'static> Clone for MessageSender<M> {
+    fn clone(&self) -> Self {
+        MessageSender {
+            inner : self.inner.clone(),
+            p : PhantomData,
+        }
+    }
+}
+
+impl<M : Send + 'static> MessageSender<M> {
+    pub fn send(&self, m : M) -> Result<(), SendError> {
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(NotifyError::Closed(None));
+        }
+        let id = self.inner.id;
+        self.inner.sender.post(move || deliver(id, Box::new(m)))
+    }
+    pub fn id(&self) -> usize {
+        self.inner.id
+    }
+}
+
+struct Timer {
+    handler : Rc<RefCell<TimeHandler+'static>>,
+    delay : u64,
+    repeat : bool,
+    timeout : Option<Timeout>,
+}
+
 pub struct Looper {
     eventers : HashMap<Token, Rc<RefCell<EventHandler+'static>>>,
     token_counter: usize,
     to_reg : Vec<Token>,
     pending : Vec<Token>,
-    timers : HashMap<TimerToken, (Rc<RefCell<TimeHandler+'static>>, u64, Option<Timeout>)>,
+    timers : HashMap<TimerToken, Timer>,
     timer_counter: usize,
     timer_to_reg : Vec<TimerToken>,
     timer_to_del : Vec<TimerToken>,
+    event_loop : Option<EventLoop<LoopHandler>>,
+    sender : LoopSender,
+    receivers : HashMap<usize, (Rc<RefCell<FnMut(Box<Any>)>>, Arc<AtomicBool>)>,
+    receiver_counter : usize,
+    shutdown_handlers : Vec<Rc<RefCell<ShutdownHandler>>>,
+    shutdown_timeout : u64,
+    shutting_down : bool,
+    deadline : Option<TimerToken>,
+    force_exit : bool,
+    /// the id `signal::watch` gave this looper
+    watch : Option<usize>,
 }
 
 impl Looper {
     pub fn new() -> Self {
+        let el = EventLoop::new().unwrap();
+        let sender = LoopSender { sender : el.channel() };
         Looper {
             eventers : HashMap::new(),
             token_counter : 0,
@@ -57,11 +175,22 @@ impl Looper {
             timer_counter : 0,
             timer_to_reg : Vec::new(),
             timer_to_del : Vec::new(),
+            event_loop : Some(el),
+            sender : sender,
+            receivers : HashMap::new(),
+            receiver_counter : 0,
+            shutdown_handlers : Vec::new(),
+            shutdown_timeout : DEFAULT_SHUTDOWN_TIMEOUT,
+            shutting_down : false,
+            deadline : None,
+            force_exit : false,
+            watch : None,
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.eventers.is_empty() && self.timers.is_empty()
+        // the drain deadline must not keep an already drained loop alive
+        self.eventers.is_empty() && self.timers.keys().all(|tt| Some(*tt) == self.deadline)
     }
 
     fn has_pending(&self) -> bool {
@@ -136,16 +265,19 @@ impl Looper {
         }
     }
 
-    pub fn register_timer(&mut self, h : Rc<RefCell<TimeHandler>>, delay : u64) -> TimerToken {
+    pub fn register_timer(&mut self, h : Rc<RefCell<TimeHandler>>, delay : u64, repeat : bool) -> TimerToken {
         let token = self.new_timer();
-        self.timers.insert(token, (h, delay, None));
+        self.timers.insert(token, Timer { handler : h, delay : delay, repeat : repeat, timeout : None });
         self.timer_to_reg.push(token);
         trace!("looper register timer {:?}", token);
         token
     }
 
     pub fn deregister_timer(&mut self, token : TimerToken) {
-        debug_assert!(self.timers.contains_key(&token));
+        if !self.timers.contains_key(&token) {
+            trace!("looper deregister timer fired? {:?}", token);
+            return;
+        }
         match self.timer_to_del.binary_search(&token) {
             Ok(_) => {
                 trace!("looper deregister timer already {:?}", token);
@@ -156,202 +288,466 @@ impl Looper {
             }
         }
     }
+
+    pub fn register_shutdown(&mut self, h : Rc<RefCell<ShutdownHandler>>) {
+        self.shutdown_handlers.push(h);
+    }
+
+    fn fire_timer(&mut self, token : TimerToken) -> Option<Rc<RefCell<TimeHandler>>> {
+        let repeat = match self.timers.get_mut(&token) {
+            None => {
+                return None;
+            }
+            Some(tmr) => {
+                tmr.timeout = None;
+                tmr.repeat
+            }
+        };
+        if repeat {
+            self.timer_to_reg.push(token);
+            self.timers.get(&token).map(|tmr| tmr.handler.clone())
+        } else {
+            self.timers.remove(&token).map(|tmr| tmr.handler)
+        }
+    }
 }
 
-pub struct LoopHandler;
+impl Drop for Looper {
+    fn drop(&mut self) {
+        // the thread is exiting, signals should no longer be posted here
+        match self.watch {
+            Some(id) => signal::unwatch(id),
+            None => {}
+        }
+    }
+}
 
-impl LoopHandler {
-    pub fn run(&mut self) {
-        let mut el = EventLoop::new().unwrap();
-        self.tick(&mut el);
-        el.run(self).unwrap();
+/// What the looper needs from whatever actually waits for events: mio's `EventLoop`, or the simulated network.
+pub trait Driver {
+    fn register(&mut self, token : Token, eventer : &Eventer, es : EventSet);
+    fn reregister(&mut self, token : Token, eventer : &Eventer, es : EventSet);
+    fn deregister(&mut self, token : Token, eventer : &Eventer);
+    fn set_timeout(&mut self, tt : TimerToken, delay : u64) -> Option<Timeout>;
+    fn clear_timeout(&mut self, tt : TimerToken, timeout : Option<Timeout>);
+}
+
+impl Driver for EventLoop<LoopHandler> {
+    fn register(&mut self, token : Token, eventer : &Eventer, es : EventSet) {
+        match eventer.evented() {
+            None => {
+                trace!("event_loop register not evented? {:?}", token);
+            }
+            Some(evented) => {
+                EventLoop::register(self, evented, token, es, PollOpt::edge()).unwrap();
+            }
+        }
+    }
+    fn reregister(&mut self, token : Token, eventer : &Eventer, es : EventSet) {
+        if let Some(evented) = eventer.evented() {
+            EventLoop::reregister(self, evented, token, es, PollOpt::edge()).unwrap();
+        }
+    }
+    fn deregister(&mut self, _token : Token, eventer : &Eventer) {
+        if let Some(evented) = eventer.evented() {
+            EventLoop::deregister(self, evented).unwrap();
+        }
+    }
+    fn set_timeout(&mut self, tt : TimerToken, delay : u64) -> Option<Timeout> {
+        Some(self.timeout_ms(tt, delay).unwrap())
+    }
+    fn clear_timeout(&mut self, _tt : TimerToken, timeout : Option<Timeout>) {
+        if let Some(to) = timeout {
+            EventLoop::clear_timeout(self, to);
+        }
+    }
+}
+
+fn loop_register<D : Driver>(el : &mut D, lp : &RefCell<Option<Looper>>) {
+    let mut borrow = lp.borrow_mut();
+    let mut looper = borrow.as_mut().unwrap();
+    if looper.to_reg.is_empty() {
+        return;
+    }
+    let mut to_reg = Vec::new();
+    swap(&mut to_reg, &mut looper.to_reg);
+    for token in to_reg {
+        match looper.get_eventer(token) {
+            None => {
+                trace!("loop_register none? {:?}", token);
+                looper.reregister(token);
+                continue;
+            }
+            Some(tt) => {
+                let t = tt.borrow();
+                let es = t.interest();
+                el.register(token, &*t, es);
+                trace!("event_loop register {:?}", token);
+            }
+        }
     }
-    fn loop_register(&mut self, el : &mut EventLoop<Self>, lp : &RefCell<Option<Looper>>) {
+}
+
+fn loop_reregister<D : Driver>(el : &mut D, lp : &RefCell<Option<Looper>>) {
+    // a Vec rather than a map, so handlers see closes in token order
+    let mut closed = Vec::new();
+    {
         let mut borrow = lp.borrow_mut();
         let mut looper = borrow.as_mut().unwrap();
-        if looper.to_reg.is_empty() {
+        if looper.pending.is_empty() {
             return;
         }
-        let mut to_reg = Vec::new();
-        swap(&mut to_reg, &mut looper.to_reg);
-        for token in to_reg {
+        let mut pending = Vec::new();
+        swap(&mut pending, &mut looper.pending);
+        for token in pending {
             match looper.get_eventer(token) {
                 None => {
-                    trace!("loop_register none? {:?}", token);
-                    looper.reregister(token);
-                    continue;
+                    trace!("loop_reregister none? {:?}", token);
+                    match looper.eventers.remove(&token) {
+                        None => {
+                        }
+                        Some(h) => {
+                            closed.push((token, h));
+                        }
+                    }
                 }
                 Some(tt) => {
-                    let t = tt.borrow();
+                    let mut t = tt.borrow_mut();
                     let es = t.interest();
-                    el.register(t.evented(), token, es, PollOpt::edge()).unwrap();
-                    trace!("event_loop register {:?}", token);
-                }
-            }
-        }
-    }
-    fn loop_reregister(&mut self, el : &mut EventLoop<Self>, lp : &RefCell<Option<Looper>>) {
-        let mut closed = HashMap::new();
-        {
-            let mut borrow = lp.borrow_mut();
-            let mut looper = borrow.as_mut().unwrap();
-            if looper.pending.is_empty() {
-                return;
-            }
-            let mut pending = Vec::new();
-            swap(&mut pending, &mut looper.pending);
-            for token in pending {
-                match looper.get_eventer(token) {
-                    None => {
-                        trace!("loop_reregister none? {:?}", token);
+                    if es == EventSet::none() {
+                        el.deregister(token, &*t);
+                        trace!("event_loop deregister {:?}", token);
                         match looper.eventers.remove(&token) {
                             None => {
                             }
                             Some(h) => {
-                                closed.insert(token, h);
-                            }
-                        }
-                    }
-                    Some(tt) => {
-                        let mut t = tt.borrow_mut();
-                        let es = t.interest();
-                        if es == EventSet::none() {
-                            el.deregister(t.evented()).unwrap();
-                            trace!("event_loop deregister {:?}", token);
-                            match looper.eventers.remove(&token) {
-                                None => {
-                                }
-                                Some(h) => {
-                                    closed.insert(token, h);
-                                }
+                                closed.push((token, h));
                             }
-                        } else if t.registered() != es {
-                            el.reregister(t.evented(), token, es, PollOpt::edge()).unwrap();
-                            trace!("event_loop reregister {:?} {:?}", token, es);
-                            t.set_registered(es);
-                        } else {
-                            trace!("event_loop reregister same? {:?} {:?}", token, es);
                         }
+                    } else if t.registered() != es {
+                        el.reregister(token, &*t, es);
+                        trace!("event_loop reregister {:?} {:?}", token, es);
+                        t.set_registered(es);
+                    } else {
+                        trace!("event_loop reregister same? {:?} {:?}", token, es);
                     }
                 }
             }
         }
-        for (token, h) in closed {
-            trace!("handler on_close {:?}", token);
-            h.borrow_mut().on_close(token);
-            trace!("handler on_close done {:?}", token);
-        }
     }
-    fn loop_register_timer(&mut self, el : &mut EventLoop<Self>, lp : &RefCell<Option<Looper>>) {
-        let mut borrow = lp.borrow_mut();
-        let mut looper = borrow.as_mut().unwrap();
-        if looper.timer_to_reg.is_empty() {
-            return;
+    for (token, h) in closed {
+        trace!("handler on_close {:?}", token);
+        h.borrow_mut().on_close(token);
+        trace!("handler on_close done {:?}", token);
+    }
+}
+
+fn loop_register_timer<D : Driver>(el : &mut D, lp : &RefCell<Option<Looper>>) {
+    let mut borrow = lp.borrow_mut();
+    let mut looper = borrow.as_mut().unwrap();
+    if looper.timer_to_reg.is_empty() {
+        return;
+    }
+    let mut timer_to_reg = Vec::new();
+    swap(&mut timer_to_reg, &mut looper.timer_to_reg);
+    for token in timer_to_reg {
+        match looper.timers.get_mut(&token) {
+            None => {
+                trace!("loop_register_timer none? {:?}", token);
+                continue;
+            }
+            Some(ref mut tmr) => {
+                let delay = tmr.delay;
+                tmr.timeout = el.set_timeout(token, delay);
+                trace!("event_loop register timer {:?} {}", token, delay);
+            }
         }
-        let mut timer_to_reg = Vec::new();
-        swap(&mut timer_to_reg, &mut looper.timer_to_reg);
-        for token in timer_to_reg {
-            match looper.timers.get_mut(&token) {
-                None => {
-                    trace!("loop_register_timer none? {:?}", token);
-                    continue;
-                }
-                Some(ref mut tmr) => {
-                    let delay = tmr.1;
-                    let to = el.timeout_ms(token, delay).unwrap();
-                    trace!("event_loop register timer {:?} {}", token, delay);
-                    tmr.2 = Some(to);
-                }
+    }
+}
+
+fn loop_deregister_timer<D : Driver>(el : &mut D, lp : &RefCell<Option<Looper>>) {
+    let mut borrow = lp.borrow_mut();
+    let mut looper = borrow.as_mut().unwrap();
+    if looper.timer_to_del.is_empty() {
+        return;
+    }
+    let mut timer_to_del = Vec::new();
+    swap(&mut timer_to_del, &mut looper.timer_to_del);
+    for token in timer_to_del {
+        match looper.timers.remove(&token) {
+            None => {
+                trace!("loop_degister_timer none? {:?}", token);
+                continue;
+            }
+            Some(tmr) => {
+                el.clear_timeout(token, tmr.timeout);
+                trace!("event_loop degister timer {:?}", token);
             }
         }
     }
-    fn loop_deregister_timer(&mut self, el : &mut EventLoop<Self>, lp : &RefCell<Option<Looper>>) {
-        let mut borrow = lp.borrow_mut();
-        let mut looper = borrow.as_mut().unwrap();
-        if looper.timer_to_del.is_empty() {
-            return;
+}
+
+/// Push every queued (re)registration and timer change to the driver.
+/// Returns false once the loop should stop.
+pub fn loop_tick<D : Driver>(el : &mut D) -> bool {
+    LOOPER.with(|looper| {
+        while looper.borrow().as_ref().unwrap().has_pending() {
+            loop_register(el, &looper);
+            loop_reregister(el, &looper);
+            loop_register_timer(el, &looper);
+            loop_deregister_timer(el, &looper);
         }
-        let mut timer_to_del = Vec::new();
-        swap(&mut timer_to_del, &mut looper.timer_to_del);
-        for token in timer_to_del {
-            match looper.timers.remove(&token) {
-                None => {
-                    trace!("loop_degister_timer none? {:?}", token);
-                    continue;
-                }
-                Some((_h, _delay, opt)) => {
-                    let to = opt.unwrap();
-                    el.clear_timeout(to);
-                    trace!("event_loop degister timer {:?}", token);
-                }
-            }
+        if looper.borrow().as_ref().unwrap().force_exit {
+            trace!("handler shutdown deadline");
+            false
+        } else if looper.borrow().as_ref().unwrap().is_empty() {
+            trace!("handler shutdown");
+            false
+        } else {
+            trace!("handler eventes {:?}, timers {:?}",
+                   looper.borrow().as_ref().unwrap().eventers.len(),
+                   looper.borrow().as_ref().unwrap().timers.len());
+            true
         }
+    })
+}
+
+pub fn loop_ready(token : Token, es : EventSet) {
+    match LOOPER.with(|looper| {
+        looper.borrow_mut().as_mut().unwrap().get_handler(token)
+    }) {
+        None => {
+            trace!("handler on_ready none? {:?} {:?}", token, es);
+        }
+        Some(h) => {
+            trace!("handler on_ready {:?} {:?}", token, es);
+            h.borrow_mut().on_ready(token, es);
+            trace!("handler on_ready done {:?}", token);
+        }
+    };
+}
+
+pub fn loop_timeout(token : TimerToken) {
+    match LOOPER.with(|looper| {
+        looper.borrow_mut().as_mut().unwrap().fire_timer(token)
+    }) {
+        None => {
+            trace!("handler on_timer none? {:?}", token);
+        }
+        Some(h) => {
+            trace!("handler on_timer {:?}", token);
+            h.borrow_mut().on_timer(token);
+            trace!("handler on_ready done {:?}", token);
+        }
+    };
+}
+
+pub struct LoopHandler;
+
+impl LoopHandler {
+    pub fn run(&mut self) {
+        let mut el = LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().event_loop.take().unwrap()
+        });
+        let wakeup = signal::wakeup_fd();
+        el.register(&EventedFd(&wakeup), SIGNAL_TOKEN, EventSet::readable(), PollOpt::edge()).unwrap();
+        self.tick(&mut el);
+        el.run(self).unwrap();
+        el.deregister(&EventedFd(&wakeup)).unwrap();
+        LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().event_loop = Some(el);
+        });
     }
 }
 
 impl Handler for LoopHandler {
     type Timeout = TimerToken;
-    type Message = ();
+    type Message = Box<LoopMessage>;
 
     fn ready(&mut self, _ : &mut EventLoop<Self>, token : Token, es : EventSet) {
-        match LOOPER.with(|looper| {
-            looper.borrow_mut().as_mut().unwrap().get_handler(token)
-        }) {
-            None => {
-                trace!("handler on_ready none? {:?} {:?}", token, es);
-            }
-            Some(h) => {
-                trace!("handler on_ready {:?} {:?}", token, es);
-                h.borrow_mut().on_ready(token, es);
-                trace!("handler on_ready done {:?}", token);
-            }
-        };
+        if token == SIGNAL_TOKEN {
+            // tick forwards the signal
+            trace!("handler signal wakeup");
+            signal::drain_wakeup();
+            return;
+        }
+        loop_ready(token, es);
     }
     fn timeout(&mut self, _ : &mut EventLoop<Self>, token : TimerToken) {
-        match LOOPER.with(|looper| {
-            looper.borrow_mut().as_mut().unwrap().timers.remove(&token)
-        }) {
-            None => {
-                trace!("handler on_timer none? {:?}", token);
-            }
-            Some((h, _, _)) => {
-                trace!("handler on_timer {:?}", token);
-                h.borrow_mut().on_timer(token);
-                trace!("handler on_ready done {:?}", token);
-            }
-        };
+        loop_timeout(token);
+    }
+    fn interrupted(&mut self, _ : &mut EventLoop<Self>) {
+        trace!("handler interrupted");
+        signal::check();
+    }
+    fn notify(&mut self, _ : &mut EventLoop<Self>, msg : Box<LoopMessage>) {
+        trace!("handler notify");
+        msg.deliver();
+        trace!("handler notify done");
     }
     fn tick(&mut self, el: &mut EventLoop<Self>) {
         trace!("handler tick");
-        LOOPER.with(|looper| {
-            while looper.borrow().as_ref().unwrap().has_pending() {
-                self.loop_register(el, &looper);
-                self.loop_reregister(el, &looper);
-                self.loop_register_timer(el, &looper);
-                self.loop_deregister_timer(el, &looper);
-            }
-            if looper.borrow().as_ref().unwrap().is_empty() {
-                trace!("handler shutdown");
-                el.shutdown();
-            } else {
-                trace!("handler eventes {:?}, timers {:?}",
-                       looper.borrow().as_ref().unwrap().eventers.len(),
-                       looper.borrow().as_ref().unwrap().timers.len());
-            }
-        });
+        signal::check();
+        if !loop_tick(el) {
+            el.shutdown();
+        }
     }
 }
 
 pub fn init() {
     env_logger::init().ok();
+    signal::install();
     LOOPER.with(|looper| {
         if looper.borrow().is_none() {
-            *looper.borrow_mut() = Some(Looper::new());
+            let mut lp = Looper::new();
+            lp.watch = Some(signal::watch(lp.sender.clone()));
+            *looper.borrow_mut() = Some(lp);
         }
     })
 }
 
+struct Deadline;
+
+impl TimeHandler for Deadline {
+    fn on_timer(&mut self, _ : TimerToken) {
+        let handlers = LOOPER.with(|looper| {
+            let mut borrow = looper.borrow_mut();
+            let looper = borrow.as_mut().unwrap();
+            looper.force_exit = true;
+            looper.shutdown_handlers.clone()
+        });
+        warn!("shutdown deadline passed, dropping pending writes");
+        for h in handlers {
+            h.borrow_mut().on_deadline();
+        }
+    }
+}
+
+/// How long a shutdown waits for pending writes to flush, in ms.
+pub fn set_shutdown_timeout(ms : u64) {
+    LOOPER.with(|looper| {
+        looper.borrow_mut().as_mut().unwrap().shutdown_timeout = ms;
+    })
+}
+
+/// Gracefully stop this thread's looper, as SIGTERM/SIGINT do.
+/// Every service stops accepting and flushes, `run_loop()` returns once they drained or the timeout passed.
+pub fn shutdown() {
+    let handlers = LOOPER.with(|looper| {
+        let mut borrow = looper.borrow_mut();
+        let looper = borrow.as_mut().unwrap();
+        if looper.shutting_down {
+            return Vec::new();
+        }
+        looper.shutting_down = true;
+        let timeout = looper.shutdown_timeout;
+        let deadline = looper.register_timer(Rc::new(RefCell::new(Deadline)), timeout, false);
+        looper.deadline = Some(deadline);
+        looper.shutdown_handlers.clone()
+    });
+    for h in handlers {
+        h.borrow_mut().on_shutdown();
+    }
+}
+
 pub fn run_loop() {
     let mut handler = LoopHandler;
     handler.run();
 }
+
+/// A handle other threads can use to post work into this thread's looper.
+/// Messages are only delivered while `run_loop()` is running; the loop still exits once it has no streams or timers.
+pub fn sender() -> LoopSender {
+    LOOPER.with(|looper| {
+        looper.borrow().as_ref().unwrap().sender.clone()
+    })
+}
+
+/// Register `f` on this thread's looper and return a sender other threads can use to feed it messages.
+pub fn receiver<M, F>(mut f : F) -> MessageSender<M>
+    where M : Send + 'static, F : FnMut(M) + 'static
+{
+    LOOPER.with(|looper| {
+        let mut borrow = looper.borrow_mut();
+        let looper = borrow.as_mut().unwrap();
+        looper.receiver_counter += 1;
+        let id = looper.receiver_counter;
+        let r = move |m : Box<Any>| {
+            match m.downcast::<M>() {
+                Ok(m) => f(*m),
+                Err(_) => trace!("receiver {} wrong message type", id),
+            }
+        };
+        let closed = Arc::new(AtomicBool::new(false));
+        looper.receivers.insert(id, (Rc::new(RefCell::new(r)), closed.clone()));
+        MessageSender {
+            inner : Arc::new(SenderInner {
+                id : id,
+                sender : looper.sender.clone(),
+                closed : closed,
+            }),
+            p : PhantomData,
+        }
+    })
+}
+
+/// Drop the receiver `id`, its senders fail from now on.
+pub fn remove_receiver(id : usize) {
+    let removed = LOOPER.with(|looper| {
+        looper.borrow_mut().as_mut().unwrap().receivers.remove(&id)
+    });
+    match removed {
+        None => {
+            trace!("remove receiver none? {}", id);
+        }
+        Some((_, closed)) => {
+            trace!("remove receiver {}", id);
+            closed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+fn deliver(id : usize, m : Box<Any>) {
+    match LOOPER.with(|looper| {
+        looper.borrow().as_ref().unwrap().receivers.get(&id).map(|&(ref r, _)| r.clone())
+    }) {
+        None => {
+            trace!("deliver none? {}", id);
+        }
+        Some(r) => {
+            (&mut *r.borrow_mut())(m);
+        }
+    }
+}
+
+/// The index of the worker loop running on this thread, if it was started by `run_workers`.
+pub fn worker_id() -> Option<usize> {
+    WORKER.with(|w| w.get())
+}
+
+/// Start `count` worker threads, each with its own looper.
+/// `setup` runs on every worker before its loop starts and should start that worker's services.
+/// Listeners bound inside a worker use SO_REUSEPORT, so the kernel spreads accepted connections across workers.
+/// Returns when every worker loop has exited.
+pub fn run_workers<F>(count : usize, setup : F)
+    where F : Fn(usize) + Send + Sync + 'static
+{
+    let setup = Arc::new(setup);
+    // signals should interrupt a worker loop rather than this joining thread
+    signal::install();
+    signal::block();
+    let workers : Vec<_> = (0..count).map(|id| {
+        let setup = setup.clone();
+        thread::Builder::new().name(format!("looper-{}", id)).spawn(move || {
+            signal::unblock();
+            WORKER.with(|w| w.set(Some(id)));
+            init();
+            setup(id);
+            run_loop();
+        }).unwrap()
+    }).collect();
+    for (id, worker) in workers.into_iter().enumerate() {
+        if worker.join().is_err() {
+            error!("worker {} panicked", id);
+        }
+    }
+}
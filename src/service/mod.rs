@@ -4,19 +4,43 @@ mod looper;
 mod stream;
 mod listen;
 mod config;
+mod error;
+mod cidr;
+mod addr;
+mod tls;
+mod socket;
+mod filter;
+mod signal;
+mod reload;
+pub mod sim;
 #[macro_use]
 mod service;
+#[macro_use]
+mod datagram;
 
 #[cfg(test)]
 mod test;
 
-pub use self::config::ServiceConfig;
+pub use self::config::{ServiceConfig, ReconnectConfig, WatermarkConfig, LimitsConfig, TlsConfig, SocketConfig};
+pub use self::error::ServiceError;
+pub use self::cidr::Cidr;
+pub use self::addr::Addr;
 pub use self::service::ServiceRef;
 pub use self::service::ServiceStreamer;
 pub use self::service::ServiceHandler;
+pub use self::service::{WriteStatus, Balance};
+pub use self::filter::{PacketFilter, Filtered};
+pub use self::datagram::{DatagramRef, DatagramHandler};
+pub use self::stream::{ConnectionInfo, ErrorKind, DisconnectReason};
+pub use self::looper::TimerToken;
 pub use self::bufwrite::BufWrite;
 pub use mio::Token;
 
 pub use self::looper::init;
 pub use self::looper::run_loop;
+pub use self::looper::run_workers;
+pub use self::looper::worker_id;
+pub use self::looper::{shutdown, set_shutdown_timeout};
+pub use self::reload::{load_config, reload, register_reload, unregister_reload, ReloadHandler};
+pub use self::looper::{sender, receiver, LoopSender, MessageSender, LoopMessage, SendError};
 
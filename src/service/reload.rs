@@ -0,0 +1,178 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::MetadataExt;
+use toml;
+
+use super::looper::{LOOPER, TimerToken, TimeHandler, ShutdownHandler};
+use super::config::parse_file;
+use super::error::ServiceError;
+use super::signal;
+
+thread_local!(static WATCH : RefCell<Option<ConfigWatch>> = RefCell::new(None));
+thread_local!(static HANDLERS : RefCell<Vec<(usize, Rc<RefCell<ReloadHandler>>)>> = RefCell::new(Vec::new()));
+thread_local!(static HANDLER_COUNTER : Cell<usize> = Cell::new(0));
+thread_local!(static POLLING : Cell<bool> = Cell::new(false));
+
+/// Told about a reload of the config file given to `load_config`.
+pub trait ReloadHandler {
+    /// `config` is the whole new file, `changed` the names of the top level sections
+    /// that were added, removed or edited since the last load.
+    fn on_reload(&mut self, config : &toml::Table, changed : &[String]);
+}
+
+struct ConfigWatch {
+    path : PathBuf,
+    config : toml::Table,
+    mtime : (i64, i64),
+    poll : Option<TimerToken>,
+}
+
+fn mtime(path : &Path) -> (i64, i64) {
+    match fs::metadata(path) {
+        Ok(m) => (m.mtime(), m.mtime_nsec()),
+        Err(_) => (0, 0),
+    }
+}
+
+/// Reads the config file this thread's services reload from, and returns it.
+/// With `poll` set, the file is checked every `poll` ms and reloaded when it was modified.
+/// From the first call on, SIGHUP reloads it in every looper of the process.
+/// Calling it again replaces the file and the poll of the earlier call.
+pub fn load_config<P : AsRef<Path>>(path : P, poll : Option<u64>) -> Result<toml::Table, ServiceError> {
+    let path = path.as_ref().to_path_buf();
+    let config = try!(parse_file(&path));
+    signal::install_hangup();
+    let timer = poll.map(|ms| {
+        LOOPER.with(|looper| {
+            let mut borrow = looper.borrow_mut();
+            let looper = borrow.as_mut().unwrap();
+            // one shutdown handler stops whichever poll is current
+            if !POLLING.with(|p| p.get()) {
+                POLLING.with(|p| p.set(true));
+                looper.register_shutdown(Rc::new(RefCell::new(ConfigPoll)));
+            }
+            looper.register_timer(Rc::new(RefCell::new(ConfigPoll)), ms, true)
+        })
+    });
+    WATCH.with(|w| {
+        let mut w = w.borrow_mut();
+        match w.take().and_then(|old| old.poll) {
+            Some(tt) => LOOPER.with(|looper| looper.borrow_mut().as_mut().unwrap().deregister_timer(tt)),
+            None => {}
+        }
+        *w = Some(ConfigWatch {
+            mtime : mtime(&path),
+            path : path,
+            config : config.clone(),
+            poll : timer,
+        });
+    });
+    Ok(config)
+}
+
+/// Have `h` told about every reload on this thread, until `unregister_reload` is called with the returned id.
+/// Services register themselves when they start and unregister when they exit or drain.
+pub fn register_reload(h : Rc<RefCell<ReloadHandler>>) -> usize {
+    let id = HANDLER_COUNTER.with(|c| {
+        let id = c.get();
+        c.set(id + 1);
+        id
+    });
+    HANDLERS.with(|handlers| handlers.borrow_mut().push((id, h)));
+    id
+}
+
+/// Forget a handler that is going away.
+pub fn unregister_reload(id : usize) {
+    HANDLERS.with(|handlers| handlers.borrow_mut().retain(|&(i, _)| i != id));
+}
+
+/// Reads the config file again and tells the handlers what changed.
+/// A file that no longer parses is logged and the running config is kept.
+pub fn reload() {
+    let r = WATCH.with(|w| {
+        let mut w = w.borrow_mut();
+        let watch = match w.as_mut() {
+            Some(watch) => watch,
+            None => {
+                trace!("reload without a config");
+                return None;
+            }
+        };
+        watch.mtime = mtime(&watch.path);
+        let config = match parse_file(&watch.path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("reload {}: {}", watch.path.display(), e);
+                return None;
+            }
+        };
+        let mut changed : Vec<String> = config.iter()
+            .filter(|&(name, value)| watch.config.get(name) != Some(value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in watch.config.keys() {
+            if !config.contains_key(name) {
+                changed.push(name.clone());
+            }
+        }
+        changed.sort();
+        info!("reload {}, changed {:?}", watch.path.display(), changed);
+        watch.config = config.clone();
+        Some((config, changed))
+    });
+    match r {
+        None => {
+        }
+        Some((config, changed)) => {
+            if changed.is_empty() {
+                return;
+            }
+            let handlers = HANDLERS.with(|handlers| handlers.borrow().clone());
+            for (id, h) in handlers {
+                // an earlier handler may have stopped this one
+                if !HANDLERS.with(|handlers| handlers.borrow().iter().any(|&(i, _)| i == id)) {
+                    continue;
+                }
+                h.borrow_mut().on_reload(&config, &changed);
+            }
+        }
+    }
+}
+
+struct ConfigPoll;
+
+impl TimeHandler for ConfigPoll {
+    fn on_timer(&mut self, _ : TimerToken) {
+        let modified = WATCH.with(|w| {
+            match *w.borrow() {
+                Some(ref watch) => mtime(&watch.path) != watch.mtime,
+                None => false,
+            }
+        });
+        if modified {
+            reload();
+        }
+    }
+}
+
+impl ShutdownHandler for ConfigPoll {
+    fn on_shutdown(&mut self) {
+        // the poll timer would keep a drained loop alive
+        WATCH.with(|w| {
+            match w.borrow_mut().as_mut() {
+                Some(watch) => {
+                    match watch.poll.take() {
+                        Some(tt) => LOOPER.with(|looper| looper.borrow_mut().as_mut().unwrap().deregister_timer(tt)),
+                        None => {}
+                    }
+                }
+                None => {}
+            }
+        });
+    }
+    fn on_deadline(&mut self) {
+    }
+}
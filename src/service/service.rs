@@ -1,55 +1,245 @@
 use std::rc::{Rc};
-use std::cell::{RefCell};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::io;
 use std::io::{Write, BufRead};
-use std::net::SocketAddr;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::fmt::Debug;
+use std::vec;
 use mio::{Token, EventSet};
-use mio::tcp::TcpStream;
+use rand;
+use toml;
+use libc;
+use openssl::ssl::SslContext;
 
-use super::looper::{LOOPER, EventHandler, Eventer, TimerToken, TimeHandler};
-use super::stream::Stream;
-use super::listen::Listen;
-use super::config::ServiceConfig;
+use super::looper::{LOOPER, EventHandler, Eventer, TimerToken, TimeHandler, ShutdownHandler, MessageSender};
+use super::looper;
+use super::stream::{Stream, Transport, ConnectionInfo, ErrorKind, DisconnectReason};
+use super::listen::{Listen, Listener};
+use super::config::{ServiceConfig, ReconnectConfig, LimitsConfig, TlsConfig, SocketConfig, Overflow};
+use super::error::ServiceError;
+use super::cidr;
+use super::cidr::Cidr;
+use super::addr::Addr;
+use super::tls;
+use super::socket;
+use super::filter;
+use super::filter::{Filtered, PacketFilter};
+use super::reload;
+use super::reload::ReloadHandler;
+use super::sim;
 
 pub trait ServiceStreamer {
     type Packet;
     type Error : Debug;
     fn write_packet(packet : &Self::Packet, writer : &mut Write) ->Result<(), Self::Error>;
     fn read_packet(reader : &mut BufRead) -> Result<Option<Self::Packet>, Self::Error>;
+    /// The length of the malformed frame behind a read error, if the stream can go on after skipping it.
+    fn skippable(_e : &Self::Error) -> Option<usize> {
+        None
+    }
 }
 
 pub trait ServiceHandler {
     type Packet;
     type Streamer : ServiceStreamer<Packet=Self::Packet>;
-    fn connected(&self, token : Token);
-    fn disconnected(&self, token : Token);
-    fn incoming(&self, token : Token, packet : Self::Packet);
-    fn outgoing(&self, token : Token, packet : &Self::Packet);
+    /// Per-connection state, created by `connected` and handed back to `disconnected`.
+    type Session;
+    fn connected(&self, token : Token) -> Self::Session;
+    fn disconnected(&self, token : Token, session : Self::Session, reason : DisconnectReason);
+    fn incoming(&self, token : Token, session : &mut Self::Session, packet : Self::Packet);
+    /// `session` is `None` before `connected` returned and in nested callbacks of the same connection.
+    fn outgoing(&self, token : Token, session : Option<&mut Self::Session>, packet : &Self::Packet);
+    /// The process is shutting down: listeners are closing and pending writes are being flushed.
+    fn shutting_down(&self) {
+    }
+    /// An outgoing connection to `addr` could not be established, `attempts` times in a row so far.
+    fn connect_failed(&self, _addr : Addr, _attempts : u32) {
+    }
+    /// No more retries to `addr`, the reconnect policy ran out of attempts.
+    fn connect_gave_up(&self, _addr : Addr) {
+    }
+    /// The listener on `addr` failed, it is rebound later according to the relisten policy.
+    fn listen_down(&self, _addr : Addr) {
+    }
+    fn listen_up(&self, _addr : Addr) {
+    }
+    /// No more rebinding `addr`, the relisten policy ran out of attempts.
+    fn listen_gave_up(&self, _addr : Addr) {
+    }
+    /// Decode and I/O errors are followed by `disconnected`, an encode error only drops the packet.
+    fn on_error(&self, _token : Token, _kind : ErrorKind) {
+    }
+    fn skipped(&self, _token : Token, _skips : u32) {
+    }
+    /// The write buffer of `token` went over the high watermark and is now down to the low one.
+    fn on_drain(&self, _token : Token) {
+    }
+    /// Returning false closes a connection from `peer` before `connected`.
+    fn accept(&self, _peer : Addr) -> bool {
+        true
+    }
+    /// The config file was reloaded and these top level sections were added or edited, other than the service's own.
+    fn reconfigured(&self, _changed : &toml::Table) {
+    }
+}
+
+/// How `ServiceRef::send_balanced` picks one of the connected outbound streams.
+#[derive(Clone, Copy, Debug)]
+pub enum Balance<'a> {
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash(&'a [u8]),
 }
 
-pub struct ServiceBody {
+/// What happened to a packet given to `ServiceRef::write`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WriteStatus {
+    Written,
+    /// not queued, the connection is over its high watermark, retry after `on_drain`
+    WouldBlock,
+    Dropped,
+    Closed,
+    NoConnection,
+}
+
+struct Connection<S> {
+    stream : Rc<RefCell<Stream>>,
+    session : Option<S>,
+}
+
+impl<S> Connection<S> {
+    fn new(stream : Stream) -> Self {
+        Connection {
+            stream : Rc::new(RefCell::new(stream)),
+            session : None,
+        }
+    }
+}
+
+const DEFAULT_LINGER : u64 = 5_000;
+
+pub struct ServiceBody<S> {
     name : String,
     listens : HashMap<Token, Rc<RefCell<Listen>>>,
-    streams : HashMap<Token, Rc<RefCell<Stream>>>,
-    connecting : HashMap<TimerToken, SocketAddr>,
+    streams : HashMap<Token, Connection<S>>,
+    connecting : HashMap<TimerToken, Addr>,
+    attempts : HashMap<Addr, u32>,
+    retries : HashMap<Addr, u32>,
+    reconnect : ReconnectConfig,
+    reconnect_to : HashMap<Addr, ReconnectConfig>,
+    relistening : HashMap<TimerToken, Addr>,
+    relisten_attempts : HashMap<Addr, u32>,
+    relisten : ReconnectConfig,
+    watermark : Option<(usize, usize, Overflow)>,
+    limits : LimitsConfig,
+    accepted : usize,
+    per_ip : HashMap<IpAddr, usize>,
+    accepting : HashMap<TimerToken, Token>,
+    groups : HashMap<String, HashSet<Token>>,
+    round_robin : usize,
+    max_skips : u32,
+    linger : u64,
+    socket : SocketConfig,
+    allow : Vec<Cidr>,
+    deny : Vec<Cidr>,
+    timers : HashMap<TimerToken, Option<Token>>,
+    tls : Option<TlsConfig>,
+    section : Option<String>,
+    reload : Option<usize>,
+    receivers : Vec<usize>,
+    tls_server : Option<Rc<SslContext>>,
+    tls_client : Option<Rc<tls::Connector>>,
 }
 
-impl ServiceBody {
+impl<S> ServiceBody<S> {
     fn new() -> Self {
         ServiceBody {
             name : String::new(),
             listens : HashMap::new(),
             streams : HashMap::new(),
             connecting : HashMap::new(),
+            attempts : HashMap::new(),
+            retries : HashMap::new(),
+            reconnect : ReconnectConfig::default(),
+            reconnect_to : HashMap::new(),
+            relistening : HashMap::new(),
+            relisten_attempts : HashMap::new(),
+            relisten : ReconnectConfig::default(),
+            watermark : None,
+            limits : LimitsConfig::default(),
+            accepted : 0,
+            per_ip : HashMap::new(),
+            accepting : HashMap::new(),
+            groups : HashMap::new(),
+            round_robin : 0,
+            max_skips : 0,
+            linger : DEFAULT_LINGER,
+            socket : SocketConfig::default(),
+            allow : Vec::new(),
+            deny : Vec::new(),
+            timers : HashMap::new(),
+            tls : None,
+            section : None,
+            reload : None,
+            receivers : Vec::new(),
+            tls_server : None,
+            tls_client : None,
+        }
+    }
+    fn reconnect_for(&self, to : &Addr) -> &ReconnectConfig {
+        self.reconnect_to.get(to).unwrap_or(&self.reconnect)
+    }
+    fn over_limit(&self, ip : Option<IpAddr>) -> Option<&'static str> {
+        match self.limits.max_connections {
+            Some(max) if self.accepted >= max => {
+                return Some("max_connections");
+            }
+            _ => {}
+        }
+        match self.limits.max_per_ip {
+            Some(max) if ip.and_then(|ip| self.per_ip.get(&ip).cloned()).unwrap_or(0) >= max => {
+                return Some("max_per_ip");
+            }
+            _ => {}
+        }
+        None
+    }
+    fn add_accepted(&mut self, ip : Option<IpAddr>) {
+        self.accepted += 1;
+        match ip {
+            Some(ip) => *self.per_ip.entry(ip).or_insert(0) += 1,
+            None => {}
+        }
+    }
+    fn remove_accepted(&mut self, ip : Option<IpAddr>) {
+        self.accepted -= 1;
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                return;
+            }
+        };
+        let last = match self.per_ip.get_mut(&ip) {
+            None => false,
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            }
+        };
+        if last {
+            self.per_ip.remove(&ip);
         }
     }
 }
 
 pub struct ServiceRef<H : ServiceHandler + 'static> {
-    service : Rc<RefCell<ServiceBody>>,
+    service : Rc<RefCell<ServiceBody<H::Session>>>,
     handler : Rc<RefCell<H>>,
+    filters : Rc<RefCell<Vec<Box<PacketFilter<H::Packet>>>>>,
+    filtering : Rc<Cell<bool>>,
 }
 
 impl<H: ServiceHandler + 'static> Clone for ServiceRef<H> {
@@ -57,6 +247,8 @@ impl<H: ServiceHandler + 'static> Clone for ServiceRef<H> {
         ServiceRef {
             service : self.service.clone(),
             handler : self.handler.clone(),
+            filters : self.filters.clone(),
+            filtering : self.filtering.clone(),
         }
     }
 }
@@ -66,31 +258,202 @@ impl<H: ServiceHandler + 'static > ServiceRef<H> {
         ServiceRef {
             service : Rc::new(RefCell::new(ServiceBody::new())),
             handler : Rc::new(RefCell::new(h)),
+            filters : Rc::new(RefCell::new(Vec::new())),
+            filtering : Rc::new(Cell::new(false)),
         }
     }
-    pub fn start(&self, config : ServiceConfig) {
-        self.service.borrow_mut().name = config.name;
-        let on_addrs : Vec<SocketAddr> = config.listen.iter().map(|on| {
-            SocketAddr::from_str(on).unwrap()
-        }).collect();
+    pub fn add_filter<F : PacketFilter<H::Packet> + 'static>(&self, f : F) {
+        self.filters.borrow_mut().push(Box::new(f));
+    }
+    pub fn start(&self, config : ServiceConfig) -> Result<(), ServiceError> {
+        let (on_addrs, to_addrs) = try!(self.configure(config));
+        // bind everything before registering anything, a failed start leaves nothing behind
+        let mut bound = Vec::new();
         for addr in on_addrs {
-            self.listen(addr);
+            let listener = try!(self.bind(&addr));
+            bound.push((addr, listener));
+        };
+        LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().register_shutdown(Rc::new(RefCell::new(self.clone())))
+        });
+        let reload = reload::register_reload(Rc::new(RefCell::new(self.clone())));
+        self.service.borrow_mut().reload = Some(reload);
+        for (addr, listener) in bound {
+            self.add_listener(addr, listener);
         };
-        let to_addrs : Vec<SocketAddr> = config.connect.iter().map(|to| {
-            SocketAddr::from_str(to).unwrap()
-        }).collect();
         for addr in to_addrs {
             self.connect(addr, true);
         };
+        Ok(())
+    }
+    /// Reconfigure the service from `section` whenever the file given to `load_config` is reloaded.
+    pub fn watch_section(&self, section : &str) {
+        self.service.borrow_mut().section = Some(section.to_string());
+    }
+    fn configure(&self, config : ServiceConfig) -> Result<(Vec<Addr>, Vec<Addr>), ServiceError> {
+        let mut on_addrs = Vec::new();
+        for on in config.listen.iter() {
+            on_addrs.push(try!(resolve(&config.name, on)));
+        }
+        let mut to_addrs = Vec::new();
+        for to in config.connect.iter() {
+            to_addrs.push(try!(resolve(&config.name, to)));
+        }
+        let watermark = match config.watermark {
+            None => None,
+            Some(ref w) => {
+                let overflow = match w.overflow() {
+                    Some(o) => o,
+                    None => {
+                        return Err(ServiceError::ConfigError(config.name.clone(), format!("unknown watermark overflow {:?}", w.overflow)));
+                    }
+                };
+                if w.low > w.high {
+                    return Err(ServiceError::ConfigError(config.name.clone(), "watermark low above high".to_string()));
+                }
+                Some((w.high, w.low, overflow))
+            }
+        };
+        let tls_server = if on_addrs.iter().any(|a| match *a { Addr::Tls(..) => true, _ => false }) {
+            Some(Rc::new(try!(tls_context(&config.name, &config.tls))))
+        } else {
+            None
+        };
+        let tls_client = if to_addrs.iter().any(|a| match *a { Addr::Tls(..) => true, _ => false }) {
+            Some(Rc::new(try!(tls_connector(&config.name, &config.tls))))
+        } else {
+            None
+        };
+        match config.socket {
+            Some(ref socket) => try!(check_socket(&config.name, socket)),
+            None => {}
+        }
+        let mut reconnect_to = HashMap::new();
+        match config.reconnect_to {
+            Some(ref policies) => {
+                for (to, policy) in policies.iter() {
+                    reconnect_to.insert(try!(resolve(&config.name, to)), policy.clone());
+                }
+            }
+            None => {}
+        }
+        let allow = try!(parse_cidrs(&config.name, &config.allow));
+        let deny = try!(parse_cidrs(&config.name, &config.deny));
+        self.service.borrow_mut().watermark = watermark;
+        self.service.borrow_mut().allow = allow;
+        self.service.borrow_mut().tls_server = tls_server;
+        self.service.borrow_mut().tls_client = tls_client;
+        self.service.borrow_mut().deny = deny;
+        self.service.borrow_mut().limits = config.limits.unwrap_or(LimitsConfig::default());
+        self.service.borrow_mut().max_skips = config.max_skips.unwrap_or(0);
+        self.service.borrow_mut().linger = config.linger.unwrap_or(DEFAULT_LINGER);
+        self.service.borrow_mut().socket = config.socket.unwrap_or(SocketConfig::default());
+        self.service.borrow_mut().name = config.name;
+        self.service.borrow_mut().reconnect = config.reconnect.unwrap_or(ReconnectConfig::default());
+        self.service.borrow_mut().reconnect_to = reconnect_to;
+        self.service.borrow_mut().relisten = config.relisten.unwrap_or(ReconnectConfig::default());
+        self.service.borrow_mut().tls = config.tls;
+        Ok((on_addrs, to_addrs))
+    }
+    /// Applies a changed config to the running service, runtime listeners and connections stay only if it lists them.
+    pub fn reconfigure(&self, config : ServiceConfig) -> Result<(), ServiceError> {
+        let (on_addrs, to_addrs) = try!(self.configure(config));
+        info!("Service {} reconfigure", self.service.borrow().name);
+        let (unlisten, relistens) = {
+            let service = self.service.borrow();
+            let unlisten : Vec<Token> = service.listens.iter()
+                .filter(|&(_, l)| {
+                    let l = l.borrow();
+                    l.relisten && !on_addrs.contains(&l.addr)
+                })
+                .map(|(token, _)| *token)
+                .collect();
+            let relistens : Vec<TimerToken> = service.relistening.iter()
+                .filter(|&(_, addr)| !on_addrs.contains(addr))
+                .map(|(tt, _)| *tt)
+                .collect();
+            (unlisten, relistens)
+        };
+        for token in unlisten {
+            self.unlisten(token);
+        }
+        for tt in relistens {
+            self.cancel_relisten(tt);
+        }
+        let mut result = Ok(());
+        for on in on_addrs {
+            let known = {
+                let service = self.service.borrow();
+                service.listens.values().any(|l| {
+                    let l = l.borrow();
+                    l.relisten && l.addr == on
+                }) || service.relistening.values().any(|addr| *addr == on)
+            };
+            if known {
+                continue;
+            }
+            info!("Service {} listen on {}", self.service.borrow().name, on);
+            match self.listen(on) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("{}", e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        let mut wanted : HashMap<Addr, usize> = HashMap::new();
+        for to in to_addrs {
+            *wanted.entry(to).or_insert(0) += 1;
+        }
+        let current = self.connect_targets();
+        for (to, have) in current.iter() {
+            let want = wanted.get(to).cloned().unwrap_or(0);
+            if *have > want {
+                info!("Service {} drop {} connections to {}", self.service.borrow().name, have - want, to);
+                self.drop_connect(to, have - want);
+            }
+        }
+        for (to, want) in wanted {
+            let have = current.get(&to).cloned().unwrap_or(0);
+            for _ in have..want {
+                info!("Service {} connect to {}", self.service.borrow().name, to);
+                self.connect(to.clone(), true);
+            }
+        }
+        result
+    }
+    fn connect_targets(&self) -> HashMap<Addr, usize> {
+        let service = self.service.borrow();
+        let mut targets = HashMap::new();
+        for c in service.streams.values() {
+            let stream = c.stream.borrow();
+            if stream.is_client && stream.reconnect {
+                *targets.entry(stream.peer_addr.clone()).or_insert(0) += 1;
+            }
+        }
+        for addr in service.connecting.values() {
+            *targets.entry(addr.clone()).or_insert(0) += 1;
+        }
+        targets
     }
     pub fn exit(&self) {
         let mut service = self.service.borrow_mut();
-        for stream in service.streams.values() {
-            stream.borrow_mut().reconnect = false;
-            stream.borrow_mut().shutdown();
+        match service.reload.take() {
+            Some(id) => reload::unregister_reload(id),
+            None => {}
+        }
+        for id in service.receivers.drain(..) {
+            looper::remove_receiver(id);
+        }
+        for conn in service.streams.values() {
+            conn.stream.borrow_mut().reconnect = false;
+            conn.stream.borrow_mut().shutdown();
         }
         //service.streams.clear();
         for listen in service.listens.values() {
+            listen.borrow_mut().relisten = false;
             listen.borrow_mut().shutdown();
         }
         //service.listens.clear();
@@ -100,114 +463,668 @@ impl<H: ServiceHandler + 'static > ServiceRef<H> {
             });
         }
         service.connecting.clear();
+        for relisten in service.relistening.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*relisten)
+            });
+        }
+        service.relistening.clear();
+        for accept in service.accepting.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*accept)
+            });
+        }
+        service.accepting.clear();
+        for tt in service.timers.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*tt)
+            });
+        }
+        service.timers.clear();
+    }
+    /// Stop accepting and reconnecting, close every stream once its pending writes are flushed.
+    pub fn drain(&self) {
+        let mut service = self.service.borrow_mut();
+        match service.reload.take() {
+            Some(id) => reload::unregister_reload(id),
+            None => {}
+        }
+        for id in service.receivers.drain(..) {
+            looper::remove_receiver(id);
+        }
+        for listen in service.listens.values() {
+            listen.borrow_mut().relisten = false;
+            listen.borrow_mut().shutdown();
+        }
+        for connect in service.connecting.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*connect)
+            });
+        }
+        service.connecting.clear();
+        for relisten in service.relistening.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*relisten)
+            });
+        }
+        service.relistening.clear();
+        for accept in service.accepting.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*accept)
+            });
+        }
+        service.accepting.clear();
+        for tt in service.timers.keys() {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(*tt)
+            });
+        }
+        service.timers.clear();
+        let tokens : Vec<Token> = service.streams.keys().cloned().collect();
+        drop(service);
+        for token in tokens {
+            self.close_after_flush(token);
+        }
     }
-    pub fn write(&self, token : Token, packet : &H::Packet) {
-        let stream = match self.service.borrow_mut().streams.get_mut(&token) {
+    pub fn write(&self, token : Token, packet : &H::Packet) -> WriteStatus {
+        self.send(token, packet, None)
+    }
+    /// Write to every connection, each one subject to its own watermark.
+    pub fn broadcast(&self, packet : &H::Packet) {
+        let tokens : Vec<Token> = self.service.borrow().streams.keys().cloned().collect();
+        self.send_all(tokens, packet);
+    }
+    /// Write to one of the connected outbound streams, returns the chosen token if the packet was queued.
+    pub fn send_balanced(&self, packet : &H::Packet, balance : Balance) -> Result<Token, WriteStatus> {
+        let token = {
+            let mut service = self.service.borrow_mut();
+            let mut candidates : Vec<(Token, Addr, usize)> = service.streams.iter()
+                .map(|(token, c)| (*token, c.stream.borrow()))
+                .filter(|&(_, ref s)| s.is_client && !s.connecting && !s.closing && s.reason.is_none())
+                .map(|(token, s)| (token, s.peer_addr.clone(), s.info().wbuf_len))
+                .collect();
+            if candidates.is_empty() {
+                trace!("service send_balanced none");
+                return Err(WriteStatus::NoConnection);
+            }
+            candidates.sort_by(|a, b| a.0.cmp(&b.0));
+            match balance {
+                Balance::RoundRobin => {
+                    service.round_robin = service.round_robin.wrapping_add(1);
+                    candidates[service.round_robin % candidates.len()].0
+                }
+                Balance::LeastOutstanding => {
+                    let mut best = (candidates[0].0, candidates[0].2);
+                    for c in candidates.iter() {
+                        if c.2 < best.1 {
+                            best = (c.0, c.2);
+                        }
+                    }
+                    best.0
+                }
+                Balance::ConsistentHash(key) => {
+                    // rendezvous hashing, only the keys of a peer that goes away move elsewhere
+                    let mut best = (candidates[0].0, 0);
+                    for c in candidates.iter() {
+                        let weight = fnv(key, format!("{}", c.1).as_bytes());
+                        if weight >= best.1 {
+                            best = (c.0, weight);
+                        }
+                    }
+                    best.0
+                }
+            }
+        };
+        trace!("service send_balanced {:?} {:?}", balance, token);
+        match self.write(token, packet) {
+            WriteStatus::Written => Ok(token),
+            status => Err(status),
+        }
+    }
+    /// Add `token` to the group `group`, the group exists while it has members.
+    pub fn join(&self, group : &str, token : Token) {
+        let mut service = self.service.borrow_mut();
+        if !service.streams.contains_key(&token) {
+            trace!("service join none {:?}", token);
+            return;
+        }
+        service.groups.entry(group.to_string()).or_insert(HashSet::new()).insert(token);
+    }
+    pub fn leave(&self, group : &str, token : Token) {
+        let mut service = self.service.borrow_mut();
+        let empty = match service.groups.get_mut(group) {
+            None => false,
+            Some(members) => {
+                members.remove(&token);
+                members.is_empty()
+            }
+        };
+        if empty {
+            service.groups.remove(group);
+        }
+    }
+    pub fn members(&self, group : &str) -> Vec<Token> {
+        match self.service.borrow().groups.get(group) {
+            None => Vec::new(),
+            Some(members) => members.iter().cloned().collect(),
+        }
+    }
+    pub fn multicast(&self, group : &str, packet : &H::Packet) {
+        let tokens = self.members(group);
+        self.send_all(tokens, packet);
+    }
+    fn send_all(&self, tokens : Vec<Token>, packet : &H::Packet) {
+        if tokens.is_empty() {
+            return;
+        }
+        let mut frame = Vec::new();
+        match H::Streamer::write_packet(packet, &mut frame) {
+            Ok(_) => {
+            }
+            Err(e) => {
+                trace!("service encode err {:?}", e);
+                for token in tokens {
+                    self.on_error(token, ErrorKind::Encode);
+                }
+                return;
+            }
+        }
+        for token in tokens {
+            self.send(token, packet, Some(&frame));
+        }
+    }
+    fn filter(&self, token : Token, packet : &H::Packet, incoming : bool) -> Filtered<H::Packet> {
+        self.filtering.set(true);
+        let filtered = filter::run(&mut self.filters.borrow_mut()[..], token, packet, incoming);
+        self.filtering.set(false);
+        filtered
+    }
+    fn send(&self, token : Token, packet : &H::Packet, frame : Option<&[u8]>) -> WriteStatus {
+        if self.filtering.get() {
+            // the chain is running, and the session of `token` may be taken
+            error!("Service {} write to {:?} from a packet filter, dropped", self.service.borrow().name, token);
+            return WriteStatus::Dropped;
+        }
+        let (stream, mut session) = match self.service.borrow_mut().streams.get_mut(&token) {
             None => {
                 trace!("service write none {:?}", token);
-                return;
+                return WriteStatus::Closed;
             }
-            Some(s) => {
-                s.clone()
+            Some(c) => {
+                (c.stream.clone(), c.session.take())
+            }
+        };
+        if stream.borrow().closing || stream.borrow().reason.is_some() {
+            self.put_session(token, session);
+            trace!("service write closing {:?}", token);
+            return WriteStatus::Closed;
+        }
+        if stream.borrow_mut().over_high() {
+            // the socket may have taken more since the last flush
+            stream.borrow_mut().flush().ok();
+        }
+        if stream.borrow_mut().over_high() {
+            self.put_session(token, session);
+            let overflow = self.service.borrow().watermark.map(|w| w.2).unwrap_or(Overflow::Block);
+            trace!("service write over watermark {:?} {:?}", token, overflow);
+            return match overflow {
+                Overflow::Block => WriteStatus::WouldBlock,
+                Overflow::Drop => WriteStatus::Dropped,
+                Overflow::Close => {
+                    info!("Service {} closing {:?} {}, peer is not reading", self.service.borrow().name, token, stream.borrow().peer_addr);
+                    stream.borrow_mut().reconnect = false;
+                    stream.borrow_mut().close(DisconnectReason::Overflow);
+                    WriteStatus::Closed
+                }
+            };
+        }
+        let filtered = self.filter(token, packet, false);
+        let (packet, frame) = match filtered {
+            Filtered::Pass => (packet, frame),
+            Filtered::Replace(ref p) => (p, None),
+            Filtered::Reply(_) | Filtered::Drop => {
+                self.put_session(token, session);
+                trace!("service write filtered {:?}", token);
+                return WriteStatus::Dropped;
             }
         };
         trace!("service handler outgoing begin {:?}", token);
-        self.handler.borrow().outgoing(token, packet);
+        self.handler.borrow().outgoing(token, session.as_mut(), packet);
         trace!("service handler outgoing end {:?}", token);
-        let r = H::Streamer::write_packet(packet, &mut *stream.borrow_mut());
+        self.put_session(token, session);
+        let r = match frame {
+            None => {
+                H::Streamer::write_packet(packet, &mut *stream.borrow_mut()).map_err(|e| format!("{:?}", e))
+            }
+            Some(frame) => {
+                stream.borrow_mut().write_all(frame).map_err(|e| format!("{:?}", e))
+            }
+        };
         match r {
             Ok(_) => {
                 trace!("service write ok {:?}", token);
+                stream.borrow_mut().packets_written += 1;
                 stream.borrow_mut().flush().ok();
+                let drained = stream.borrow_mut().drained();
+                if drained {
+                    trace!("service handler on_drain begin {:?}", token);
+                    self.handler.borrow().on_drain(token);
+                    trace!("service handler on_drain end {:?}", token);
+                }
+                WriteStatus::Written
             }
             Err(e) => {
                 trace!("service write err {:?} {:?}", token, e);
+                self.on_error(token, ErrorKind::Encode);
+                WriteStatus::Dropped
             }
         }
     }
-    pub fn broadcast(&self, packet : &H::Packet) {
-        let streams = self.service.borrow_mut().streams.clone();
-        for (token, stream) in streams {
-            trace!("service handler outgoing begin {:?}", token);
-            self.handler.borrow().outgoing(token, packet);
-            trace!("service handler outgoing end {:?}", token);
-            let r = H::Streamer::write_packet(packet, &mut *stream.borrow_mut());
-            match r {
-                Ok(_) => {
-                    trace!("service write ok {:?}", token);
-                    stream.borrow_mut().flush().ok();
-                }
-                Err(e) => {
-                    trace!("service write err {:?} {:?}", token, e);
-                }
+    /// Close `token` right away, the same as `abort`. Writes still queued are dropped,
+    pub fn shutdown(&self, token : Token) {
+        self.abort(token);
+    }
+    /// Send what is already written, then half-close so the peer reads the end of the stream.
+    pub fn close_after_flush(&self, token : Token) {
+        let stream = match self.service.borrow().streams.get(&token) {
+            None => {
+                trace!("service close_after_flush none {:?}", token);
+                return;
+            }
+            Some(c) => {
+                c.stream.clone()
             }
+        };
+        {
+            let mut stream = stream.borrow_mut();
+            if stream.closing {
+                return;
+            }
+            trace!("service close_after_flush {:?}", token);
+            stream.reconnect = false;
+            stream.close_after_flush();
         }
+        let linger = self.service.borrow().linger;
+        let service = self.clone();
+        self.set_timer(linger, false, Some(token), move |_ : &H| {
+            trace!("service linger timeout {:?}", token);
+            service.abort(token);
+        });
     }
-    pub fn shutdown(&self, token : Token) {
+    pub fn abort(&self, token : Token) {
         match self.service.borrow_mut().streams.get_mut(&token) {
             None => {
-                trace!("service shutdown none {:?}", token);
+                trace!("service abort none {:?}", token);
                 return;
             }
-            Some(s) => {
-                trace!("service shutdown {:?}", token);
-                s.borrow_mut().reconnect = false;
-                s.borrow_mut().shutdown();
+            Some(c) => {
+                trace!("service abort {:?}", token);
+                c.stream.borrow_mut().reconnect = false;
+                c.stream.borrow_mut().shutdown();
             }
         };
     }
+    /// Like `receiver()`, but the receiver goes away when the service exits or drains.
+    pub fn receiver<M, F>(&self, f : F) -> MessageSender<M>
+        where M : Send + 'static, F : FnMut(M) + 'static
+    {
+        let sender = looper::receiver(f);
+        self.service.borrow_mut().receivers.push(sender.id());
+        sender
+    }
     pub fn streams_count(&self) -> usize {
         self.service.borrow().streams.len()
     }
-    fn listen(&self, on : SocketAddr) {
+    pub fn connection_info(&self, token : Token) -> Option<ConnectionInfo> {
+        self.service.borrow().streams.get(&token).map(|c| c.stream.borrow().info())
+    }
+    pub fn connections(&self) -> vec::IntoIter<ConnectionInfo> {
+        let infos : Vec<ConnectionInfo> = self.service.borrow().streams.values()
+            .map(|c| c.stream.borrow().info())
+            .collect();
+        infos.into_iter()
+    }
+    fn take_session(&self, token : Token) -> Option<H::Session> {
+        match self.service.borrow_mut().streams.get_mut(&token) {
+            None => None,
+            Some(c) => c.session.take(),
+        }
+    }
+    fn put_session(&self, token : Token, session : Option<H::Session>) {
+        match session {
+            None => {
+            }
+            Some(s) => {
+                match self.service.borrow_mut().streams.get_mut(&token) {
+                    None => {
+                    }
+                    Some(c) => {
+                        c.session = Some(s);
+                    }
+                }
+            }
+        }
+    }
+    fn on_error(&self, token : Token, kind : ErrorKind) {
+        trace!("service handler on_error begin {:?} {:?}", token, kind);
+        self.handler.borrow().on_error(token, kind);
+        trace!("service handler on_error end {:?}", token);
+    }
+    /// Call `f` with the handler after `delay` ms, and then every `delay` ms if `repeat`.
+    pub fn set_timer<F>(&self, delay : u64, repeat : bool, token : Option<Token>, f : F) -> TimerToken
+        where F : FnMut(&H) + 'static
+    {
+        let timer = ServiceTimer {
+            service : self.clone(),
+            repeat : repeat,
+            callback : Box::new(f),
+        };
+        let tt = LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().register_timer(Rc::new(RefCell::new(timer)), delay, repeat)
+        });
+        self.service.borrow_mut().timers.insert(tt, token);
+        tt
+    }
+    pub fn cancel_timer(&self, tt : TimerToken) {
+        match self.service.borrow_mut().timers.remove(&tt) {
+            None => {
+                trace!("service cancel timer none {:?}", tt);
+            }
+            Some(_) => {
+                LOOPER.with(|looper| {
+                    looper.borrow_mut().as_mut().unwrap().deregister_timer(tt)
+                });
+            }
+        }
+    }
+    fn cancel_timers_of(&self, token : Token) {
+        let tts : Vec<TimerToken> = self.service.borrow().timers.iter()
+            .filter(|&(_, t)| *t == Some(token))
+            .map(|(tt, _)| *tt)
+            .collect();
+        for tt in tts {
+            trace!("service cancel timer {:?} of {:?}", tt, token);
+            self.cancel_timer(tt);
+        }
+    }
+    /// Starts listening on `addr`, given as in the `listen` list of the config, while the service runs.
+    pub fn add_listen(&self, addr : &str) -> Result<Token, ServiceError> {
+        let name = self.service.borrow().name.clone();
+        let on = try!(resolve(&name, addr));
+        try!(self.ensure_tls(&on, true));
+        info!("Service {} listen on {}", name, on);
+        self.listen(on)
+    }
+    pub fn unlisten(&self, token : Token) -> bool {
+        let service = self.service.borrow();
+        match service.listens.get(&token) {
+            None => false,
+            Some(listen) => {
+                let mut listen = listen.borrow_mut();
+                info!("Service {} unlisten on {}", service.name, listen.addr);
+                listen.relisten = false;
+                listen.shutdown();
+                true
+            }
+        }
+    }
+    /// Stops listening on `addr`, connections accepted there stay open.
+    pub fn remove_listen(&self, addr : &str) -> Result<bool, ServiceError> {
+        let name = self.service.borrow().name.clone();
+        let on = try!(resolve(&name, addr));
+        let (tokens, relistens) = {
+            let service = self.service.borrow();
+            let tokens : Vec<Token> = service.listens.iter()
+                .filter(|&(_, l)| {
+                    let l = l.borrow();
+                    l.relisten && l.addr == on
+                })
+                .map(|(token, _)| *token)
+                .collect();
+            let relistens : Vec<TimerToken> = service.relistening.iter()
+                .filter(|&(_, a)| *a == on)
+                .map(|(tt, _)| *tt)
+                .collect();
+            (tokens, relistens)
+        };
+        info!("Service {} stop listening on {}", name, on);
+        let removed = !tokens.is_empty() || !relistens.is_empty();
+        for token in tokens {
+            self.unlisten(token);
+        }
+        for tt in relistens {
+            self.cancel_relisten(tt);
+        }
+        self.service.borrow_mut().relisten_attempts.remove(&on);
+        Ok(removed)
+    }
+    fn cancel_relisten(&self, tt : TimerToken) {
+        let addr = self.service.borrow_mut().relistening.remove(&tt);
+        match addr {
+            Some(addr) => {
+                self.service.borrow_mut().relisten_attempts.remove(&addr);
+            }
+            None => {}
+        }
+        LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().deregister_timer(tt)
+        });
+    }
+    /// Connects to `addr` while the service runs, kept up by the reconnect policy like the configured ones.
+    pub fn add_connect(&self, addr : &str) -> Result<Option<Token>, ServiceError> {
+        let name = self.service.borrow().name.clone();
+        let to = try!(resolve(&name, addr));
+        try!(self.ensure_tls(&to, false));
+        info!("Service {} connect to {}", name, to);
+        Ok(self.connect(to, true))
+    }
+    /// Stops connecting to `addr`, returns how many connections were closed.
+    pub fn remove_connect(&self, addr : &str) -> Result<usize, ServiceError> {
+        let name = self.service.borrow().name.clone();
+        let to = try!(resolve(&name, addr));
+        info!("Service {} stop connecting to {}", name, to);
+        self.service.borrow_mut().attempts.remove(&to);
+        self.service.borrow_mut().retries.remove(&to);
+        Ok(self.drop_connect(&to, usize::max_value()))
+    }
+    /// Stops up to `count` of the connections kept to `to`, pending reconnects first.
+    fn drop_connect(&self, to : &Addr, count : usize) -> usize {
+        let (tokens, timers) = {
+            let mut service = self.service.borrow_mut();
+            let mut timers : Vec<TimerToken> = service.connecting.iter()
+                .filter(|&(_, a)| *a == *to)
+                .map(|(tt, _)| *tt)
+                .collect();
+            timers.sort();
+            timers.truncate(count);
+            for tt in timers.iter() {
+                service.connecting.remove(tt);
+            }
+            let mut tokens : Vec<Token> = service.streams.iter()
+                .filter(|&(_, c)| {
+                    let stream = c.stream.borrow();
+                    stream.is_client && stream.reconnect && stream.peer_addr == *to
+                })
+                .map(|(token, _)| *token)
+                .collect();
+            // the newest connections go first
+            tokens.sort_by(|a, b| b.cmp(a));
+            tokens.truncate(count - timers.len());
+            (tokens, timers)
+        };
+        for tt in timers {
+            LOOPER.with(|looper| {
+                looper.borrow_mut().as_mut().unwrap().deregister_timer(tt)
+            });
+        }
+        for token in tokens.iter() {
+            // closing gracefully also turns reconnect off
+            self.close_after_flush(*token);
+        }
+        tokens.len()
+    }
+    fn ensure_tls(&self, addr : &Addr, server : bool) -> Result<(), ServiceError> {
+        match *addr {
+            Addr::Tls(..) => {}
+            _ => {
+                return Ok(());
+            }
+        }
+        let mut service = self.service.borrow_mut();
+        if server && service.tls_server.is_none() {
+            service.tls_server = Some(Rc::new(try!(tls_context(&service.name, &service.tls))));
+        } else if !server && service.tls_client.is_none() {
+            service.tls_client = Some(Rc::new(try!(tls_connector(&service.name, &service.tls))));
+        }
+        Ok(())
+    }
+    fn listen(&self, on : Addr) -> Result<Token, ServiceError> {
+        let listener = try!(self.bind(&on));
+        Ok(self.add_listener(on, listener))
+    }
+    fn bind(&self, on : &Addr) -> Result<Listener, ServiceError> {
+        let tls = self.service.borrow().tls_server.clone();
+        let bound = Listener::bind(on, tls, &self.service.borrow().socket);
+        match bound {
+            Ok(l) => Ok(l),
+            Err(e) => Err(ServiceError::BindError(self.service.borrow().name.clone(), on.clone(), e)),
+        }
+    }
+    fn add_listener(&self, on : Addr, listener : Listener) -> Token {
         let c : ServiceRef<H> = self.clone();
         let token = LOOPER.with(|looper| {
             looper.borrow_mut().as_mut().unwrap().register(Rc::new(RefCell::new(c)))
         });
-        self.service.borrow_mut().listens.insert(token, Rc::new(RefCell::new(Listen::new(token, on))));
+        self.service.borrow_mut().listens.insert(token, Rc::new(RefCell::new(Listen::new(token, on, listener))));
+        token
+    }
+    fn set_options(&self, transport : &Transport, peer : &Addr) {
+        let fd = match transport.tcp_fd() {
+            Some(fd) => fd,
+            None => {
+                return;
+            }
+        };
+        let service = self.service.borrow();
+        match socket::apply(fd, &service.socket) {
+            Ok(()) => {}
+            Err(e) => {
+                info!("Service {} socket options for {} err {:?}", service.name, peer, e);
+            }
+        }
     }
-    fn connect(&self, to : SocketAddr, reconnect : bool) {
+    fn connect(&self, to : Addr, reconnect : bool) -> Option<Token> {
+        let (tls, options) = {
+            let service = self.service.borrow();
+            (service.tls_client.clone(), service.socket.clone())
+        };
+        let transport = match Transport::connect(&to, tls.as_ref().map(|connector| &**connector), &options) {
+            Ok(t) => t,
+            Err(e) => {
+                info!("Service {} connect to {} err {:?}", self.service.borrow().name, to, e);
+                if reconnect {
+                    self.connect_failed(to);
+                }
+                return None;
+            }
+        };
         let token = LOOPER.with(|looper| {
             looper.borrow_mut().as_mut().unwrap().register(Rc::new(RefCell::new(self.clone())))
         });
-        let stream = Stream::new(token, TcpStream::connect(&to).unwrap(), true, reconnect, to);
-        self.service.borrow_mut().streams.insert(token, Rc::new(RefCell::new(stream)));
+        let mut stream = Stream::new(token, transport, true, reconnect, to);
+        let mut service = self.service.borrow_mut();
+        match service.watermark {
+            Some((high, low, _)) => stream.set_watermark(high, low),
+            None => {}
+        }
+        service.streams.insert(token, Connection::new(stream));
+        Some(token)
     }
-    fn timer_connect(&self, to : SocketAddr) {
+    fn connect_failed(&self, to : Addr) {
+        let (attempts, gave_up) = {
+            let mut service = self.service.borrow_mut();
+            let attempts = {
+                let n = service.attempts.entry(to.clone()).or_insert(0);
+                *n += 1;
+                *n
+            };
+            let gave_up = service.reconnect_for(&to).gave_up(attempts);
+            (attempts, gave_up)
+        };
+        trace!("service handler connect_failed begin {}", to);
+        self.handler.borrow().connect_failed(to.clone(), attempts);
+        trace!("service handler connect_failed end {}", to);
+        if gave_up {
+            info!("Service {} gave up connecting to {} after {} attempts", self.service.borrow().name, to, attempts);
+            self.service.borrow_mut().attempts.remove(&to);
+            self.service.borrow_mut().retries.remove(&to);
+            self.handler.borrow().connect_gave_up(to.clone());
+        } else {
+            self.timer_connect(to);
+        }
+    }
+    fn timer_connect(&self, to : Addr) {
+        let delay = {
+            let mut service = self.service.borrow_mut();
+            // the first retry after a drop and the one after a failed first connect both wait delay(1)
+            let retry = {
+                let n = service.retries.entry(to.clone()).or_insert(0);
+                *n += 1;
+                *n
+            };
+            let random = if sim::active() { sim::random() } else { rand::random::<f64>() };
+            service.reconnect_for(&to).delay(retry, random)
+        };
+        trace!("service reconnect {} in {}", to, delay);
         let token = LOOPER.with(|looper| {
-            looper.borrow_mut().as_mut().unwrap().register_timer(Rc::new(RefCell::new(self.clone())), 5_000)
+            looper.borrow_mut().as_mut().unwrap().register_timer(Rc::new(RefCell::new(self.clone())), delay, false)
         });
         self.service.borrow_mut().connecting.insert(token, to);
     }
     fn on_ready_stream(&self, token : Token, es : EventSet) -> bool {
         let mut  new_connected = false;
+        let mut drained = false;
+        let mut skips = Vec::new();
         let mut packets = Vec::new();
         {
-            let service = self.service.borrow();
+            let mut service = &mut *self.service.borrow_mut();
             match service.streams.get(&token) {
                 None => {
                     return false;
                 }
-                Some(s) => {
-                    let mut stream = s.borrow_mut();
+                Some(c) => {
+                    let mut stream = c.stream.borrow_mut();
                     let got = stream.got;
                     stream.got = es;
                     if es.is_error() || es.is_hup() {
-                        stream.shutdown();
+                        let kind = match stream.stream.take_error() {
+                            Some(e) => ErrorKind::Io(e.kind()),
+                            None if es.is_error() => ErrorKind::Io(io::ErrorKind::Other),
+                            None => ErrorKind::Hangup,
+                        };
+                        stream.close(DisconnectReason::Error(kind));
                     } else {
                         if es.is_writable() {
                             if stream.connecting {
                                 info!("Service {} connected to {:?} {}", service.name, token, stream.peer_addr);
                                 new_connected = true;
-                                stream.connecting = false;
+                                stream.set_connected();
+                                if stream.is_client {
+                                    service.attempts.remove(&stream.peer_addr);
+                                    service.retries.remove(&stream.peer_addr);
+                                }
                             }
                             stream.flush().ok();
+                            drained = stream.drained();
                         }
-                        if es.is_readable() {
+                        // nothing is decoded before `connected` made the session, data that came early
+                        // is still in the socket and read as soon as the connection is up
+                        if (es.is_readable() || new_connected) && !stream.connecting {
                             trace!("stream read");
                             loop {
                                 match H::Streamer::read_packet(&mut *stream) {
                                     Ok(Some(p)) => {
+                                        stream.packets_read += 1;
                                         packets.push(p);
                                     }
                                     Ok(None) => {
@@ -215,8 +1132,17 @@ impl<H: ServiceHandler + 'static > ServiceRef<H> {
                                     }
                                     Err(e) => {
                                         trace!("service read err {:?} {:?}", token, e);
-                                        stream.shutdown();
-                                        break;
+                                        match H::Streamer::skippable(&e) {
+                                            Some(len) if stream.skips < service.max_skips => {
+                                                stream.skips += 1;
+                                                stream.consume(len);
+                                                skips.push(stream.skips);
+                                            }
+                                            _ => {
+                                                stream.close(DisconnectReason::Error(ErrorKind::Decode));
+                                                break;
+                                            }
+                                        }
                                     }
                                 }
                             }
@@ -227,87 +1153,221 @@ impl<H: ServiceHandler + 'static > ServiceRef<H> {
         }
         if new_connected {
             trace!("service handler connected begin {:?}", token);
-            self.handler.borrow().connected(token);
+            let session = self.handler.borrow().connected(token);
             trace!("service handler connected end {:?}", token);
+            self.put_session(token, Some(session));
+        }
+        for n in skips {
+            info!("Service {} skipped a bad frame from {:?}, {} so far", self.service.borrow().name, token, n);
+            trace!("service handler skipped begin {:?}", token);
+            self.handler.borrow().skipped(token, n);
+            trace!("service handler skipped end {:?}", token);
+        }
+        if drained {
+            trace!("service handler on_drain begin {:?}", token);
+            self.handler.borrow().on_drain(token);
+            trace!("service handler on_drain end {:?}", token);
         }
+        if packets.is_empty() {
+            return true;
+        }
+        let mut session = match self.take_session(token) {
+            None => {
+                let service = self.service.borrow();
+                match service.streams.get(&token) {
+                    Some(c) => {
+                        error!("Service {} lost {} packets from {:?}, it has no session, closing", service.name, packets.len(), token);
+                        c.stream.borrow_mut().close(DisconnectReason::Local);
+                    }
+                    None => {
+                        trace!("service incoming after close {:?}", token);
+                    }
+                }
+                return true;
+            }
+            Some(s) => s,
+        };
         for packet in packets {
+            let filtered = self.filter(token, &packet, true);
+            let packet = match filtered {
+                Filtered::Pass => packet,
+                Filtered::Replace(p) => p,
+                Filtered::Drop => {
+                    trace!("service incoming filtered {:?}", token);
+                    continue;
+                }
+                Filtered::Reply(p) => {
+                    trace!("service incoming filtered with reply {:?}", token);
+                    self.write(token, &p);
+                    continue;
+                }
+            };
             trace!("service handler incoming begin {:?}", token);
-            self.handler.borrow().incoming(token, packet);
+            self.handler.borrow().incoming(token, &mut session, packet);
             trace!("service handler incoming end {:?}", token);
         }
+        self.put_session(token, Some(session));
         true
     }
     fn on_ready_listen(&self, token : Token, es : EventSet) -> bool {
-        let mut service = &mut *self.service.borrow_mut();
-        let listens = &service.listens;
-        let mut streams = &mut service.streams;
-        match listens.get(&token) {
+        let listen = match self.service.borrow().listens.get(&token) {
             None => {
-                false
+                return false;
             }
             Some(s) => {
-                let mut listen = s.borrow_mut();
-                if es.is_error() || es.is_hup() {
-                    trace!("listen error?");
-                    listen.shutdown();
-                } else {
-                    if es.is_writable() {
-                        trace!("listen writable?");
-                    }
-                    if es.is_readable() {
-                        trace!("listen read");
-                        loop {
-                            match listen.listener.accept() {
-                                Ok(Some((stream, peer))) => {
-                                    let token = LOOPER.with(|looper| {
-                                        looper.borrow_mut().as_mut().unwrap().register(Rc::new(RefCell::new(self.clone())))
-                                    });
-                                    streams.insert(token,
-                                        Rc::new(RefCell::new(Stream::new(token, stream, false, false, peer))));
-                                }
-                                Ok(None) => {
-                                    trace!("listen accept none");
-                                    break;
-                                }
-                                Err(e) => {
-                                    trace!("listen accept err {:?}", e);
-                                }
-                            }
+                s.clone()
+            }
+        };
+        if es.is_error() || es.is_hup() {
+            trace!("listen error?");
+            listen.borrow_mut().shutdown();
+        } else {
+            if es.is_writable() {
+                trace!("listen writable?");
+            }
+            if es.is_readable() {
+                trace!("listen read");
+                self.accept_pending(token, &mut *listen.borrow_mut());
+            }
+        }
+        true
+    }
+    fn accept_pending(&self, token : Token, listen : &mut Listen) {
+        let mut accepted = 0;
+        loop {
+            let throttled = match self.service.borrow().limits.max_accepts_per_tick {
+                Some(max) => accepted >= max,
+                None => false,
+            };
+            if throttled {
+                // the listener is edge triggered, what is left in the backlog is picked up by a timer
+                trace!("listen accept throttled {:?}", token);
+                let tt = LOOPER.with(|looper| {
+                    looper.borrow_mut().as_mut().unwrap().register_timer(Rc::new(RefCell::new(self.clone())), 0, false)
+                });
+                self.service.borrow_mut().accepting.insert(tt, token);
+                break;
+            }
+            match listen.listener.accept() {
+                Ok(Some((stream, peer))) => {
+                    accepted += 1;
+                    let rejected = {
+                        let service = self.service.borrow();
+                        let denied = match peer.ip() {
+                            Some(ip) => !cidr::allowed(&ip, &service.allow, &service.deny),
+                            None => false,
+                        };
+                        if denied {
+                            Some("allow/deny lists")
+                        } else {
+                            service.over_limit(peer.ip())
+                        }
+                    };
+                    match rejected {
+                        Some(why) => {
+                            info!("Service {} rejected {} on {}, {}", self.service.borrow().name, peer, listen.addr, why);
+                            continue;
                         }
+                        None => {}
+                    }
+                    trace!("service handler accept begin {}", peer);
+                    let ok = self.handler.borrow().accept(peer.clone());
+                    trace!("service handler accept end {}", peer);
+                    if !ok {
+                        info!("Service {} rejected {} on {}, by handler", self.service.borrow().name, peer, listen.addr);
+                        continue;
+                    }
+                    self.set_options(&stream, &peer);
+                    let token = LOOPER.with(|looper| {
+                        looper.borrow_mut().as_mut().unwrap().register(Rc::new(RefCell::new(self.clone())))
+                    });
+                    let mut service = self.service.borrow_mut();
+                    let ip = peer.ip();
+                    let mut stream = Stream::new(token, stream, false, false, peer);
+                    match service.watermark {
+                        Some((high, low, _)) => stream.set_watermark(high, low),
+                        None => {}
                     }
+                    service.add_accepted(ip);
+                    service.streams.insert(token, Connection::new(stream));
+                }
+                Ok(None) => {
+                    trace!("listen accept none");
+                    break;
+                }
+                Err(e) => {
+                    trace!("listen accept err {:?}", e);
+                    break;
                 }
-                true
             }
         }
     }
     fn on_close_stream(&self, token : Token) -> bool {
-        let addr = {
+        let (addr, was_connected, session, reason) = {
             let mut service = self.service.borrow_mut();
             let r = service.streams.remove(&token);
             match r {
                 None => {
                     return false;
                 }
-                Some(s) => {
-                    let stream = s.borrow();
+                Some(c) => {
+                    let stream = c.stream.borrow();
+                    if !stream.is_client {
+                        service.remove_accepted(stream.peer_addr.ip());
+                    }
+                    let mut emptied = Vec::new();
+                    for (group, members) in service.groups.iter_mut() {
+                        if members.remove(&token) && members.is_empty() {
+                            emptied.push(group.clone());
+                        }
+                    }
+                    for group in emptied {
+                        service.groups.remove(&group);
+                    }
                     if stream.is_client && stream.reconnect {
                         info!("Service {} disconnected from {:?} {}", service.name, token, stream.peer_addr);
-                        Some(stream.peer_addr)
+                        (Some(stream.peer_addr.clone()), !stream.connecting, c.session, stream.reason)
                     } else {
-                        None
+                        (None, !stream.connecting, c.session, stream.reason)
                     }
                 }
             }
         };
         match addr {
             Some(addr) => {
-                self.timer_connect(addr);
+                if was_connected {
+                    self.timer_connect(addr);
+                } else {
+                    self.connect_failed(addr);
+                }
             }
             None => {
             }
         }
-        trace!("service handler disconnected begin {:?}", token);
-        self.handler.borrow().disconnected(token);
+        self.cancel_timers_of(token);
+        for f in self.filters.borrow_mut().iter_mut() {
+            f.closed(token);
+        }
+        let session = match session {
+            Some(s) => s,
+            None => {
+                // connected never fired for it, so neither does disconnected
+                return true;
+            }
+        };
+        let reason = reason.unwrap_or(DisconnectReason::Local);
+        match reason {
+            DisconnectReason::Error(ErrorKind::Hangup) => {
+                // the peer closed, that is how connections end
+            }
+            DisconnectReason::Error(kind) => {
+                self.on_error(token, kind);
+            }
+            _ => {
+            }
+        }
+        trace!("service handler disconnected begin {:?} {:?}", token, reason);
+        self.handler.borrow().disconnected(token, session, reason);
         trace!("service handler disconnected end {:?}", token);
         true
     }
@@ -315,21 +1375,77 @@ impl<H: ServiceHandler + 'static > ServiceRef<H> {
         let r = self.service.borrow_mut().listens.remove(&token);
         match r {
             None => false,
-            Some(_) => {
+            Some(l) => {
                 trace!("service close listen {:?}", token);
-                    //TODO re-listen?
+                let (addr, relisten) = {
+                    let listen = l.borrow();
+                    (listen.addr.clone(), listen.relisten)
+                };
+                if relisten {
+                    info!("Service {} listen on {} went down", self.service.borrow().name, addr);
+                    // scheduled first, so that the handler can still call it off with remove_listen
+                    self.timer_relisten(addr.clone());
+                    trace!("service handler listen_down begin {}", addr);
+                    self.handler.borrow().listen_down(addr.clone());
+                    trace!("service handler listen_down end {}", addr);
+                }
                 true
             }
         }
     }
+    fn relisten(&self, on : Addr) {
+        match self.listen(on.clone()) {
+            Ok(_) => {
+                info!("Service {} listen on {} is back", self.service.borrow().name, on);
+                self.service.borrow_mut().relisten_attempts.remove(&on);
+                trace!("service handler listen_up begin {}", on);
+                self.handler.borrow().listen_up(on.clone());
+                trace!("service handler listen_up end {}", on);
+            }
+            Err(e) => {
+                info!("{}", e);
+                let gave_up = {
+                    let service = self.service.borrow();
+                    let attempts = service.relisten_attempts.get(&on).cloned().unwrap_or(0);
+                    service.relisten.gave_up(attempts)
+                };
+                if gave_up {
+                    error!("Service {} gave up listening on {}", self.service.borrow().name, on);
+                    self.service.borrow_mut().relisten_attempts.remove(&on);
+                    trace!("service handler listen_gave_up begin {}", on);
+                    self.handler.borrow().listen_gave_up(on.clone());
+                    trace!("service handler listen_gave_up end {}", on);
+                } else {
+                    self.timer_relisten(on);
+                }
+            }
+        }
+    }
+    fn timer_relisten(&self, on : Addr) {
+        let delay = {
+            let mut service = self.service.borrow_mut();
+            let attempt = {
+                let n = service.relisten_attempts.entry(on.clone()).or_insert(0);
+                *n += 1;
+                *n
+            };
+            let random = if sim::active() { sim::random() } else { rand::random::<f64>() };
+            service.relisten.delay(attempt, random)
+        };
+        trace!("service relisten {} in {}", on, delay);
+        let token = LOOPER.with(|looper| {
+            looper.borrow_mut().as_mut().unwrap().register_timer(Rc::new(RefCell::new(self.clone())), delay, false)
+        });
+        self.service.borrow_mut().relistening.insert(token, on);
+    }
 }
 
 impl<H: ServiceHandler + 'static> EventHandler for ServiceRef<H> {
     fn get_eventer(&mut self, token : Token) -> Option<Rc<RefCell<Eventer>>> {
         let service = self.service.borrow();
         match service.streams.get(&token) {
-            Some(stream) => {
-                Some(stream.clone())
+            Some(c) => {
+                Some(c.stream.clone())
             }
             None => {
                 match service.listens.get(&token) {
@@ -356,6 +1472,57 @@ impl<H: ServiceHandler + 'static> EventHandler for ServiceRef<H> {
     }
 }
 
+impl<H: ServiceHandler + 'static> ShutdownHandler for ServiceRef<H> {
+    fn on_shutdown(&mut self) {
+        info!("Service {} shutting down", self.service.borrow().name);
+        trace!("service handler shutting_down begin");
+        self.handler.borrow().shutting_down();
+        trace!("service handler shutting_down end");
+        self.drain();
+    }
+    fn on_deadline(&mut self) {
+        self.exit();
+    }
+}
+
+impl<H: ServiceHandler + 'static> ReloadHandler for ServiceRef<H> {
+    fn on_reload(&mut self, config : &toml::Table, changed : &[String]) {
+        let section = self.service.borrow().section.clone();
+        let mut others = toml::Table::new();
+        for name in changed {
+            if Some(name) == section.as_ref() {
+                continue;
+            }
+            match config.get(name) {
+                Some(value) => {
+                    others.insert(name.clone(), value.clone());
+                }
+                None => {}
+            }
+        }
+        match section {
+            Some(ref section) if changed.contains(section) => {
+                let r = match config.get(section) {
+                    Some(value) => ServiceConfig::from_toml(value.clone()).and_then(|c| self.reconfigure(c)),
+                    None => Err(ServiceError::MissingSection(section.clone())),
+                };
+                match r {
+                    Ok(()) => {}
+                    Err(e) => {
+                        error!("Service {} reload: {}", self.service.borrow().name, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !others.is_empty() {
+            trace!("service handler reconfigured begin {:?}", others.keys().collect::<Vec<_>>());
+            self.handler.borrow().reconfigured(&others);
+            trace!("service handler reconfigured end");
+        }
+    }
+}
+
 impl<H: ServiceHandler + 'static> TimeHandler for ServiceRef<H> {
     fn on_timer(&mut self, token : TimerToken) {
         let r = self.service.borrow_mut().connecting.remove(&token);
@@ -364,11 +1531,152 @@ impl<H: ServiceHandler + 'static> TimeHandler for ServiceRef<H> {
             }
             Some(addr) => {
                 self.connect(addr, true);
+                return;
+            }
+        }
+        let r = self.service.borrow_mut().relistening.remove(&token);
+        match r {
+            None => {
+            }
+            Some(addr) => {
+                self.relisten(addr);
+                return;
+            }
+        }
+        let r = self.service.borrow_mut().accepting.remove(&token);
+        match r {
+            None => {
+            }
+            Some(token) => {
+                let listen = self.service.borrow().listens.get(&token).cloned();
+                match listen {
+                    None => {
+                    }
+                    Some(listen) => {
+                        self.accept_pending(token, &mut *listen.borrow_mut());
+                    }
+                }
             }
         }
     }
 }
 
+struct ServiceTimer<H : ServiceHandler + 'static> {
+    service : ServiceRef<H>,
+    repeat : bool,
+    callback : Box<FnMut(&H)>,
+}
+
+impl<H: ServiceHandler + 'static> TimeHandler for ServiceTimer<H> {
+    fn on_timer(&mut self, tt : TimerToken) {
+        if !self.repeat {
+            self.service.service.borrow_mut().timers.remove(&tt);
+        }
+        trace!("service handler timer begin {:?}", tt);
+        (self.callback)(&*self.service.handler.borrow());
+        trace!("service handler timer end {:?}", tt);
+    }
+}
+
+fn fnv(key : &[u8], peer : &[u8]) -> u64 {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for b in key.iter().chain(&[0u8]).chain(peer.iter()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn parse_cidrs(name : &str, cidrs : &Option<Vec<String>>) -> Result<Vec<Cidr>, ServiceError> {
+    let mut parsed = Vec::new();
+    match *cidrs {
+        None => {
+        }
+        Some(ref cidrs) => {
+            for c in cidrs.iter() {
+                match Cidr::from_str(c) {
+                    Ok(c) => parsed.push(c),
+                    Err(_) => {
+                        return Err(ServiceError::ConfigError(name.to_string(), format!("bad address block {:?}", c)));
+                    }
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn check_socket(name : &str, socket : &SocketConfig) -> Result<(), ServiceError> {
+    let max = libc::c_int::max_value() as u64;
+    let checks = [
+        ("keepalive", socket.keepalive.map(|v| v as u64), 1),
+        ("keepalive_interval", socket.keepalive_interval.map(|v| v as u64), 1),
+        ("keepalive_count", socket.keepalive_count.map(|v| v as u64), 1),
+        ("send_buffer", socket.send_buffer.map(|v| v as u64), 0),
+        ("recv_buffer", socket.recv_buffer.map(|v| v as u64), 0),
+    ];
+    for &(option, value, min) in checks.iter() {
+        match value {
+            Some(v) if v < min || v > max => {
+                return Err(ServiceError::ConfigError(name.to_string(), format!("socket {} {} is out of range {}..{}", option, v, min, max)));
+            }
+            _ => {}
+        }
+    }
+    match socket.backlog {
+        Some(backlog) if backlog < 1 => {
+            return Err(ServiceError::ConfigError(name.to_string(), format!("socket backlog {} is below 1", backlog)));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn tls_context(name : &str, config : &Option<TlsConfig>) -> Result<SslContext, ServiceError> {
+    match *config {
+        Some(ref config) => tls::context(name, config, true),
+        None => Err(ServiceError::ConfigError(name.to_string(), "tls: addresses need a tls section".to_string())),
+    }
+}
+
+fn tls_connector(name : &str, config : &Option<TlsConfig>) -> Result<tls::Connector, ServiceError> {
+    match *config {
+        Some(ref config) => tls::connector(name, config),
+        None => Err(ServiceError::ConfigError(name.to_string(), "tls: addresses need a tls section".to_string())),
+    }
+}
+
+/// `unix:/path` names a unix domain socket, `tls:` in front of an address puts TLS on the TCP connection.
+pub fn resolve(name : &str, addr : &str) -> Result<Addr, ServiceError> {
+    if addr.starts_with("unix:") {
+        return Ok(Addr::Unix(PathBuf::from(&addr[5..])));
+    }
+    let (is_tls, host) = if addr.starts_with("tls:") {
+        (true, &addr[4..])
+    } else {
+        (false, addr)
+    };
+    let mut addrs = match host.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            return Err(ServiceError::ResolveError(name.to_string(), addr.to_string(), e));
+        }
+    };
+    match addrs.next() {
+        Some(a) if is_tls => {
+            // what the server certificate is checked against, "[::1]:443" is for "::1"
+            let server = host.rsplitn(2, ':').last().unwrap_or(host);
+            let server = server.trim_left_matches('[').trim_right_matches(']');
+            Ok(Addr::Tls(a, server.to_string()))
+        }
+        Some(a) => Ok(Addr::Tcp(a)),
+        None => {
+            let e = io::Error::new(io::ErrorKind::NotFound, "no address found");
+            Err(ServiceError::ResolveError(name.to_string(), addr.to_string(), e))
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! service_define {
     ($n:ident : $t:ty) => {
@@ -397,7 +1705,7 @@ macro_rules! service_exit {
 #[macro_export]
 macro_rules! service_write {
     ($n:ident , $t:expr, $p:expr) => {
-        $n.with(|s| s.borrow_mut().as_mut().unwrap().write($t, $p))
+        $n.with(|s| s.borrow().as_ref().unwrap().write($t, $p))
     }
 }
 #[macro_export]
@@ -407,9 +1715,102 @@ macro_rules! service_broadcast {
     }
 }
 #[macro_export]
+macro_rules! service_send_balanced {
+    ($n:ident, $p:expr, $b:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().send_balanced($p, $b))
+    }
+}
+#[macro_export]
+macro_rules! service_join {
+    ($n:ident, $g:expr, $t:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().join($g, $t))
+    }
+}
+#[macro_export]
+macro_rules! service_leave {
+    ($n:ident, $g:expr, $t:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().leave($g, $t))
+    }
+}
+#[macro_export]
+macro_rules! service_multicast {
+    ($n:ident, $g:expr, $p:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().multicast($g, $p))
+    }
+}
+#[macro_export]
+macro_rules! service_timer {
+    ($n:ident , $d:expr, $r:expr, $t:expr, $f:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().set_timer($d, $r, $t, $f))
+    };
+    ($n:ident , $d:expr, $f:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().set_timer($d, false, None, $f))
+    };
+}
+#[macro_export]
+macro_rules! service_receiver {
+    ($n:ident, $f:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().receiver($f))
+    }
+}
+#[macro_export]
+macro_rules! service_cancel_timer {
+    ($n:ident , $tt:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().cancel_timer($tt))
+    }
+}
+#[macro_export]
+macro_rules! service_abort {
+    ($n:ident, $t:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().abort($t))
+    }
+}
+#[macro_export]
 macro_rules! service_shutdown {
     ($n:ident , $t:expr) => {
         $n.with(|s| s.borrow_mut().as_mut().unwrap().shutdown($t))
     }
 }
+#[macro_export]
+macro_rules! service_close_after_flush {
+    ($n:ident , $t:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().close_after_flush($t))
+    }
+}
 
+#[macro_export]
+macro_rules! service_add_listen {
+    ($n:ident, $a:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().add_listen($a))
+    }
+}
+#[macro_export]
+macro_rules! service_unlisten {
+    ($n:ident, $t:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().unlisten($t))
+    }
+}
+#[macro_export]
+macro_rules! service_remove_listen {
+    ($n:ident, $a:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().remove_listen($a))
+    }
+}
+#[macro_export]
+macro_rules! service_add_connect {
+    ($n:ident, $a:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().add_connect($a))
+    }
+}
+#[macro_export]
+macro_rules! service_remove_connect {
+    ($n:ident, $a:expr) => {
+        $n.with(|s| s.borrow_mut().as_mut().unwrap().remove_connect($a))
+    }
+}
+#[macro_export]
+macro_rules! service_filter {
+    ($n:ident, $f:expr) => {
+        $n.with(|s| s.borrow().as_ref().unwrap().add_filter($f))
+    }
+}
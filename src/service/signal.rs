@@ -0,0 +1,158 @@
+use std::io;
+use std::ptr;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use libc;
+
+use super::looper::{LoopSender, shutdown};
+use super::reload::reload;
+
+static SIGNALED : AtomicBool = ATOMIC_BOOL_INIT;
+static HANGUP : AtomicBool = ATOMIC_BOOL_INIT;
+static INSTALL : Once = ONCE_INIT;
+static INSTALL_HANGUP : Once = ONCE_INIT;
+static WATCH_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+static PIPE : Once = ONCE_INIT;
+static WAKE_READ : AtomicUsize = ATOMIC_USIZE_INIT;
+static WAKE_WRITE : AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref LOOPERS : Mutex<Vec<(usize, LoopSender)>> = Mutex::new(Vec::new());
+}
+
+extern "C" fn on_signal(_ : libc::c_int) {
+    // only async-signal-safe work here, the loopers pick the flag up on their next tick.
+    SIGNALED.store(true, Ordering::SeqCst);
+    wake();
+}
+
+extern "C" fn on_hangup(_ : libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+    wake();
+}
+
+// The signal may land on any thread, not only one running a loop. Every loop polls the read end
+// of this pipe, so an idle one still ticks and forwards the signal.
+fn open_pipe() {
+    PIPE.call_once(|| {
+        let mut fds = [0 as libc::c_int; 2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) < 0 {
+                panic!("signal pipe: {}", io::Error::last_os_error());
+            }
+            for fd in fds.iter() {
+                libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        WAKE_READ.store(fds[0] as usize, Ordering::SeqCst);
+        WAKE_WRITE.store(fds[1] as usize, Ordering::SeqCst);
+    });
+}
+
+fn wake() {
+    let fd = WAKE_WRITE.load(Ordering::SeqCst) as libc::c_int;
+    let b = 1u8;
+    unsafe {
+        // a full pipe already wakes the loops
+        libc::write(fd, &b as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+/// The fd loops poll to hear about signals.
+pub fn wakeup_fd() -> RawFd {
+    open_pipe();
+    WAKE_READ.load(Ordering::SeqCst) as RawFd
+}
+
+pub fn drain_wakeup() {
+    let fd = wakeup_fd();
+    let mut buf = [0u8; 64];
+    loop {
+        let n = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if n <= 0 {
+            break;
+        }
+    }
+}
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set : libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Install the SIGTERM/SIGINT handlers once per process.
+pub fn install() {
+    open_pipe();
+    INSTALL.call_once(|| {
+        unsafe {
+            libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+            libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        }
+    });
+}
+
+/// Install the SIGHUP handler once per process. Only done once there is a config file to reload,
+/// until then SIGHUP keeps its default action.
+pub fn install_hangup() {
+    open_pipe();
+    INSTALL_HANGUP.call_once(|| {
+        unsafe {
+            libc::signal(libc::SIGHUP, on_hangup as libc::sighandler_t);
+        }
+    });
+}
+
+/// Keep SIGTERM/SIGINT/SIGHUP away from this thread, e.g. a thread that only joins workers.
+pub fn block() {
+    let set = signal_set();
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+}
+
+pub fn unblock() {
+    let set = signal_set();
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+    }
+}
+
+/// Remember a looper so it gets told about a signal caught by any thread, until `unwatch` is called with the returned id.
+pub fn watch(sender : LoopSender) -> usize {
+    let id = WATCH_COUNTER.fetch_add(1, Ordering::SeqCst);
+    LOOPERS.lock().unwrap().push((id, sender));
+    id
+}
+
+/// Forget a looper that is going away.
+pub fn unwatch(id : usize) {
+    LOOPERS.lock().unwrap().retain(|&(i, _)| i != id);
+}
+
+/// Called on every loop tick, forwards a caught signal to every looper in the process.
+pub fn check() {
+    if HANGUP.swap(false, Ordering::SeqCst) {
+        info!("SIGHUP caught, reloading config");
+        for &(_, ref sender) in LOOPERS.lock().unwrap().iter() {
+            sender.post(|| reload()).ok();
+        }
+    }
+    if !SIGNALED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    info!("signal caught, shutting down");
+    for &(_, ref sender) in LOOPERS.lock().unwrap().iter() {
+        sender.post(|| shutdown()).ok();
+    }
+}
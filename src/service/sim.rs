@@ -0,0 +1,689 @@
+//! Deterministic simulation: an in-memory network and a virtual clock standing in for mio.
+//!
+//! ```ignore
+//! init();
+//! sim::start(SimConfig::new(42));
+//! service_start!(FRONT_SERVICE, front, config);
+//! sim::run();
+//! ```
+//! While a simulation is running on a thread, every `Listen` and outgoing connect of that thread's services
+//! goes through the simulated network, and looper timers run on virtual time.
+//! Latency, packet splits and disconnects are drawn from per-connection generators seeded from `SimConfig::seed`,
+//! so a run replays exactly as long as the handlers themselves are deterministic.
+
+use std::cell::RefCell;
+use std::cmp::{min, Ordering};
+use std::collections::{HashMap, BinaryHeap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr, Shutdown};
+use mio::{Token, EventSet, Timeout};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::looper::{Eventer, Driver, TimerToken, loop_tick, loop_ready, loop_timeout};
+
+thread_local!(static SIM : RefCell<Option<SimNet>> = RefCell::new(None));
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub seed : u64,
+    /// one way latency of every segment, in virtual ms, drawn from `latency_min..latency_max+1`
+    pub latency_min : u64,
+    pub latency_max : u64,
+    /// chance that a write is cut into one more segment at a random offset
+    pub split : f64,
+    /// chance that a segment never arrives and the connection is reset instead
+    pub disconnect : f64,
+}
+
+impl SimConfig {
+    pub fn new(seed : u64) -> Self {
+        SimConfig {
+            seed : seed,
+            latency_min : 1,
+            latency_max : 10,
+            split : 0.0,
+            disconnect : 0.0,
+        }
+    }
+}
+
+enum Event {
+    Ready(usize, EventSet),
+    Deliver(usize, Vec<u8>),
+    Eof(usize),
+    Reset(usize),
+    Connect(usize, SocketAddr),
+    Established(usize),
+    Timer(TimerToken, u64),
+}
+
+struct Scheduled {
+    time : u64,
+    id : usize,
+    seq : u64,
+    event : Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other : &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other : &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // reversed, BinaryHeap pops the earliest event first
+    fn cmp(&self, other : &Self) -> Ordering {
+        (other.time, other.id, other.seq).cmp(&(self.time, self.id, self.seq))
+    }
+}
+
+struct Endpoint {
+    token : Option<Token>,
+    peer : Option<usize>,
+    rng : XorShiftRng,
+    inbox : VecDeque<u8>,
+    connected : bool,
+    eof : bool,
+    reset : bool,
+    read_closed : bool,
+    write_closed : bool,
+    last_delivery : u64,
+}
+
+struct Listener {
+    token : Option<Token>,
+    addr : SocketAddr,
+    backlog : VecDeque<(usize, SocketAddr)>,
+}
+
+struct SimNet {
+    config : SimConfig,
+    now : u64,
+    seq : u64,
+    queue : BinaryHeap<Scheduled>,
+    endpoints : HashMap<usize, Endpoint>,
+    listeners : HashMap<usize, Listener>,
+    bound : HashMap<SocketAddr, usize>,
+    addrs : HashMap<usize, SocketAddr>,
+    timers : HashMap<TimerToken, u64>,
+    id_counter : usize,
+    port_counter : u16,
+    rng : XorShiftRng,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "simulated stream closed")
+}
+
+impl SimNet {
+    fn new(config : SimConfig) -> Self {
+        let rng = XorShiftRng::from_seed([config.seed as u32 | 1, (config.seed >> 32) as u32, 0, 0x9E3779B9]);
+        SimNet {
+            config : config,
+            now : 0,
+            seq : 0,
+            queue : BinaryHeap::new(),
+            endpoints : HashMap::new(),
+            listeners : HashMap::new(),
+            bound : HashMap::new(),
+            addrs : HashMap::new(),
+            timers : HashMap::new(),
+            id_counter : 0,
+            port_counter : 10000,
+            rng : rng,
+        }
+    }
+
+    fn schedule(&mut self, time : u64, id : usize, event : Event) {
+        self.seq += 1;
+        self.queue.push(Scheduled { time : time, id : id, seq : self.seq, event : event });
+    }
+
+    fn ready(&mut self, id : usize, es : EventSet) {
+        let now = self.now;
+        self.schedule(now, id, Event::Ready(id, es));
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.id_counter += 1;
+        self.id_counter
+    }
+
+    fn new_endpoint(&mut self) -> usize {
+        let id = self.new_id();
+        let seed = self.config.seed;
+        let rng = XorShiftRng::from_seed([seed as u32 | 1, (seed >> 32) as u32, id as u32, 0x9E3779B9]);
+        self.endpoints.insert(id, Endpoint {
+            token : None,
+            peer : None,
+            rng : rng,
+            inbox : VecDeque::new(),
+            connected : false,
+            eof : false,
+            reset : false,
+            read_closed : false,
+            write_closed : false,
+            last_delivery : 0,
+        });
+        id
+    }
+
+    fn latency(&mut self, id : usize) -> u64 {
+        let (lo, hi) = (self.config.latency_min, self.config.latency_max);
+        let ep = self.endpoints.get_mut(&id).unwrap();
+        if hi > lo {
+            ep.rng.gen_range(lo, hi + 1)
+        } else {
+            lo
+        }
+    }
+
+    fn bind(&mut self, addr : &SocketAddr) -> io::Result<usize> {
+        if self.bound.contains_key(addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "simulated address in use"));
+        }
+        let id = self.new_id();
+        self.listeners.insert(id, Listener { token : None, addr : *addr, backlog : VecDeque::new() });
+        self.bound.insert(*addr, id);
+        Ok(id)
+    }
+
+    fn connect(&mut self, addr : &SocketAddr) -> usize {
+        let id = self.new_endpoint();
+        self.port_counter = self.port_counter.wrapping_add(1);
+        let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), self.port_counter));
+        self.addrs.insert(id, local);
+        let time = self.now + self.latency(id);
+        self.schedule(time, id, Event::Connect(id, *addr));
+        id
+    }
+
+    fn accept(&mut self, id : usize) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.listeners.get_mut(&id) {
+            None => Err(closed()),
+            Some(l) => Ok(l.backlog.pop_front()),
+        }
+    }
+
+    fn attach(&mut self, id : usize, token : Token) {
+        let waiting = match self.listeners.get_mut(&id) {
+            None => None,
+            Some(l) => {
+                l.token = Some(token);
+                Some(!l.backlog.is_empty())
+            }
+        };
+        match waiting {
+            None => {
+            }
+            Some(true) => {
+                self.ready(id, EventSet::readable());
+                return;
+            }
+            Some(false) => {
+                return;
+            }
+        }
+        let es = match self.endpoints.get_mut(&id) {
+            None => {
+                return;
+            }
+            Some(ep) => {
+                ep.token = Some(token);
+                let mut es = EventSet::none();
+                if ep.connected {
+                    es = es | EventSet::writable();
+                }
+                if !ep.inbox.is_empty() || ep.eof {
+                    es = es | EventSet::readable();
+                }
+                if ep.reset {
+                    es = es | EventSet::hup();
+                }
+                es
+            }
+        };
+        if es != EventSet::none() {
+            self.ready(id, es);
+        }
+    }
+
+    fn detach(&mut self, id : usize) {
+        if let Some(l) = self.listeners.remove(&id) {
+            self.bound.remove(&l.addr);
+        }
+        if let Some(ep) = self.endpoints.get_mut(&id) {
+            ep.token = None;
+        }
+    }
+
+    fn read(&mut self, id : usize, buf : &mut [u8]) -> io::Result<usize> {
+        let ep = match self.endpoints.get_mut(&id) {
+            None => return Err(closed()),
+            Some(ep) => ep,
+        };
+        if ep.reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "simulated reset"));
+        }
+        if ep.read_closed {
+            return Ok(0);
+        }
+        if ep.inbox.is_empty() {
+            if ep.eof {
+                return Ok(0);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "simulated would block"));
+        }
+        let len = min(buf.len(), ep.inbox.len());
+        for (i, b) in ep.inbox.drain(..len).enumerate() {
+            buf[i] = b;
+        }
+        Ok(len)
+    }
+
+    fn take_error(&self, id : usize) -> Option<io::Error> {
+        match self.endpoints.get(&id) {
+            Some(ep) if ep.reset => {
+                if ep.connected {
+                    Some(io::Error::new(io::ErrorKind::ConnectionReset, "simulated reset"))
+                } else {
+                    Some(io::Error::new(io::ErrorKind::ConnectionRefused, "simulated refused"))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, id : usize, buf : &[u8]) -> io::Result<usize> {
+        let (peer, mut segments) = {
+            let ep = match self.endpoints.get_mut(&id) {
+                None => return Err(closed()),
+                Some(ep) => ep,
+            };
+            if ep.reset {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "simulated reset"));
+            }
+            if ep.write_closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "simulated write closed"));
+            }
+            if !ep.connected {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "simulated not connected"));
+            }
+            let mut segments = vec![buf.len()];
+            while buf.len() > segments.len() && ep.rng.gen::<f64>() < self.config.split {
+                let last = segments.pop().unwrap();
+                if last < 2 {
+                    segments.push(last);
+                    break;
+                }
+                let cut = ep.rng.gen_range(1, last);
+                segments.push(cut);
+                segments.push(last - cut);
+            }
+            (ep.peer, segments)
+        };
+        let peer = match peer {
+            None => return Err(closed()),
+            Some(peer) => peer,
+        };
+        let mut offset = 0;
+        for len in segments.drain(..) {
+            let latency = self.latency(id);
+            let lost = self.endpoints.get_mut(&id).unwrap().rng.gen::<f64>() < self.config.disconnect;
+            let time = {
+                let ep = self.endpoints.get_mut(&id).unwrap();
+                ep.last_delivery = if ep.last_delivery > self.now + latency { ep.last_delivery } else { self.now + latency };
+                ep.last_delivery
+            };
+            if lost {
+                trace!("sim {} lost segment, reset", id);
+                self.schedule(time, id, Event::Reset(id));
+                break;
+            }
+            self.schedule(time, peer, Event::Deliver(peer, buf[offset..offset + len].to_vec()));
+            offset += len;
+        }
+        Ok(buf.len())
+    }
+
+    fn shutdown(&mut self, id : usize, how : Shutdown) {
+        let (peer, time) = {
+            let now = self.now;
+            let ep = match self.endpoints.get_mut(&id) {
+                None => return,
+                Some(ep) => ep,
+            };
+            if how != Shutdown::Write {
+                ep.read_closed = true;
+            }
+            if how == Shutdown::Read || ep.write_closed {
+                return;
+            }
+            ep.write_closed = true;
+            // the fin follows whatever is still in flight
+            (ep.peer, if ep.last_delivery > now { ep.last_delivery } else { now })
+        };
+        if let Some(peer) = peer {
+            self.schedule(time, peer, Event::Eof(peer));
+        }
+    }
+
+    fn disconnect(&mut self, token : Token) {
+        let id = self.endpoints.iter().find(|&(_, ep)| ep.token == Some(token)).map(|(id, _)| *id);
+        if let Some(id) = id {
+            let now = self.now;
+            self.schedule(now, id, Event::Reset(id));
+            return;
+        }
+        let id = self.listeners.iter().find(|&(_, l)| l.token == Some(token)).map(|(id, _)| *id);
+        if let Some(id) = id {
+            let now = self.now;
+            self.schedule(now, id, Event::Ready(id, EventSet::hup()));
+        }
+    }
+
+    fn handle(&mut self, event : Event) -> Fired {
+        match event {
+            Event::Ready(id, es) => {
+                let token = self.endpoints.get(&id).and_then(|ep| ep.token)
+                    .or_else(|| self.listeners.get(&id).and_then(|l| l.token));
+                Fired::ready(token, es)
+            }
+            Event::Deliver(id, bytes) => {
+                match self.endpoints.get_mut(&id) {
+                    None => Fired::Nothing,
+                    Some(ep) => {
+                        if ep.reset || ep.read_closed {
+                            Fired::Nothing
+                        } else {
+                            ep.inbox.extend(bytes);
+                            Fired::ready(ep.token, EventSet::readable())
+                        }
+                    }
+                }
+            }
+            Event::Eof(id) => {
+                match self.endpoints.get_mut(&id) {
+                    Some(ep) => {
+                        ep.eof = true;
+                        Fired::ready(ep.token, EventSet::readable())
+                    }
+                    None => Fired::Nothing,
+                }
+            }
+            Event::Reset(id) => {
+                let peer = match self.endpoints.get_mut(&id) {
+                    None => return Fired::Nothing,
+                    Some(ep) => {
+                        if ep.reset {
+                            return Fired::Nothing;
+                        }
+                        ep.reset = true;
+                        ep.peer
+                    }
+                };
+                if let Some(peer) = peer {
+                    if let Some(p) = self.endpoints.get_mut(&peer) {
+                        p.reset = true;
+                    }
+                    self.ready(peer, EventSet::hup());
+                }
+                Fired::ready(self.endpoints.get(&id).and_then(|ep| ep.token), EventSet::hup())
+            }
+            Event::Connect(client, addr) => {
+                match self.bound.get(&addr).cloned() {
+                    None => {
+                        trace!("sim {} connect refused {}", client, addr);
+                        match self.endpoints.get_mut(&client) {
+                            Some(ep) => {
+                                ep.reset = true;
+                                Fired::ready(ep.token, EventSet::hup() | EventSet::error())
+                            }
+                            None => Fired::Nothing,
+                        }
+                    }
+                    Some(listener) => {
+                        let server = self.new_endpoint();
+                        let peer_addr = self.addrs.get(&client).cloned().unwrap();
+                        {
+                            let ep = self.endpoints.get_mut(&server).unwrap();
+                            ep.peer = Some(client);
+                            ep.connected = true;
+                        }
+                        if let Some(ep) = self.endpoints.get_mut(&client) {
+                            ep.peer = Some(server);
+                        }
+                        self.listeners.get_mut(&listener).unwrap().backlog.push_back((server, peer_addr));
+                        // the client learns about the handshake one more hop later
+                        let time = self.now + self.latency(client);
+                        self.schedule(time, client, Event::Established(client));
+                        Fired::ready(self.listeners.get(&listener).unwrap().token, EventSet::readable())
+                    }
+                }
+            }
+            Event::Established(id) => {
+                match self.endpoints.get_mut(&id) {
+                    None => Fired::Nothing,
+                    Some(ep) => {
+                        if ep.reset {
+                            Fired::Nothing
+                        } else {
+                            ep.connected = true;
+                            Fired::ready(ep.token, EventSet::writable())
+                        }
+                    }
+                }
+            }
+            Event::Timer(tt, generation) => {
+                if self.timers.get(&tt) == Some(&generation) {
+                    self.timers.remove(&tt);
+                    Fired::Timer(tt)
+                } else {
+                    Fired::Nothing
+                }
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<Fired> {
+        self.queue.pop().map(|ev| {
+            if ev.time > self.now {
+                self.now = ev.time;
+            }
+            self.handle(ev.event)
+        })
+    }
+}
+
+enum Fired {
+    Nothing,
+    Ready(Token, EventSet),
+    Timer(TimerToken),
+}
+
+impl Fired {
+    fn ready(token : Option<Token>, es : EventSet) -> Self {
+        match token {
+            None => Fired::Nothing,
+            Some(t) => Fired::Ready(t, es),
+        }
+    }
+}
+
+fn with<F, T>(f : F) -> T
+    where F : FnOnce(&mut SimNet) -> T
+{
+    SIM.with(|sim| f(sim.borrow_mut().as_mut().expect("simulation not started")))
+}
+
+/// A connected or connecting simulated stream.
+pub struct SimStream {
+    id : usize,
+}
+
+impl SimStream {
+    pub fn connect(addr : &SocketAddr) -> SimStream {
+        SimStream { id : with(|net| net.connect(addr)) }
+    }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn shutdown(&self, how : Shutdown) {
+        with(|net| net.shutdown(self.id, how))
+    }
+    /// The error a reset or refused connection would have left in SO_ERROR.
+    pub fn take_error(&self) -> Option<io::Error> {
+        with(|net| net.take_error(self.id))
+    }
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        with(|net| net.read(self.id, buf))
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        with(|net| net.write(self.id, buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        SIM.with(|sim| {
+            if let Some(net) = sim.borrow_mut().as_mut() {
+                net.shutdown(self.id, Shutdown::Both);
+            }
+        });
+    }
+}
+
+/// A simulated listening address.
+pub struct SimListener {
+    id : usize,
+}
+
+impl SimListener {
+    pub fn bind(addr : &SocketAddr) -> io::Result<SimListener> {
+        with(|net| net.bind(addr)).map(|id| SimListener { id : id })
+    }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn accept(&self) -> io::Result<Option<(SimStream, SocketAddr)>> {
+        with(|net| net.accept(self.id)).map(|r| r.map(|(id, peer)| (SimStream { id : id }, peer)))
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        SIM.with(|sim| {
+            if let Some(net) = sim.borrow_mut().as_mut() {
+                net.detach(self.id);
+            }
+        });
+    }
+}
+
+struct SimDriver;
+
+impl Driver for SimDriver {
+    fn register(&mut self, token : Token, eventer : &Eventer, _es : EventSet) {
+        if let Some(id) = eventer.sim_id() {
+            with(|net| net.attach(id, token));
+        }
+    }
+    fn reregister(&mut self, _token : Token, _eventer : &Eventer, _es : EventSet) {
+    }
+    fn deregister(&mut self, _token : Token, eventer : &Eventer) {
+        if let Some(id) = eventer.sim_id() {
+            with(|net| {
+                if let Some(ep) = net.endpoints.get_mut(&id) {
+                    ep.token = None;
+                }
+            });
+        }
+    }
+    fn set_timeout(&mut self, tt : TimerToken, delay : u64) -> Option<Timeout> {
+        with(|net| {
+            net.seq += 1;
+            let generation = net.seq;
+            net.timers.insert(tt, generation);
+            let time = net.now + delay;
+            net.schedule(time, tt.0, Event::Timer(tt, generation));
+        });
+        None
+    }
+    fn clear_timeout(&mut self, tt : TimerToken, _timeout : Option<Timeout>) {
+        with(|net| {
+            net.timers.remove(&tt);
+        });
+    }
+}
+
+/// Route this thread's services through a fresh simulated network. Call after `init()` and before starting services.
+pub fn start(config : SimConfig) {
+    SIM.with(|sim| {
+        *sim.borrow_mut() = Some(SimNet::new(config));
+    })
+}
+
+pub fn active() -> bool {
+    SIM.with(|sim| sim.borrow().is_some())
+}
+
+/// The virtual time in ms since `start`.
+pub fn now() -> u64 {
+    with(|net| net.now)
+}
+
+/// A number in `[0, 1)` from the simulation's own generator, for framework decisions like reconnect jitter.
+pub fn random() -> f64 {
+    with(|net| net.rng.gen::<f64>())
+}
+
+/// Reset the connection behind `token`, both ends see a hangup.
+/// When `token` is a listener it fails instead, as if its socket went bad.
+pub fn disconnect(token : Token) {
+    with(|net| net.disconnect(token))
+}
+
+/// Drive the looper on virtual time until it runs out of work, as `run_loop()` would. Returns the final virtual time.
+pub fn run() -> u64 {
+    let mut driver = SimDriver;
+    while loop_tick(&mut driver) {
+        let fired = with(|net| net.next());
+        match fired {
+            None => {
+                trace!("sim idle at {}", now());
+                break;
+            }
+            Some(Fired::Nothing) => {
+            }
+            Some(Fired::Ready(token, es)) => {
+                loop_ready(token, es);
+            }
+            Some(Fired::Timer(tt)) => {
+                loop_timeout(tt);
+            }
+        }
+    }
+    let end = now();
+    SIM.with(|sim| {
+        *sim.borrow_mut() = None;
+    });
+    end
+}
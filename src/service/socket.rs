@@ -0,0 +1,82 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use libc;
+
+use super::config::SocketConfig;
+
+fn setsockopt(fd : RawFd, level : libc::c_int, name : libc::c_int, value : libc::c_int) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// SO_ERROR, what went wrong on the socket. Reading it clears it.
+pub fn take_error(fd : RawFd) -> io::Result<Option<io::Error>> {
+    let mut value : libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ERROR, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else if value == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(io::Error::from_raw_os_error(value)))
+    }
+}
+
+/// SO_SNDBUF/SO_RCVBUF, also set on listening sockets so accepted ones start with them.
+pub fn set_buffers(fd : RawFd, config : &SocketConfig) -> io::Result<()> {
+    match config.send_buffer {
+        Some(size) => try!(setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)),
+        None => {}
+    }
+    match config.recv_buffer {
+        Some(size) => try!(setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)),
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive_probes(fd : RawFd, idle : u32, config : &SocketConfig) -> io::Result<()> {
+    try!(setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle as libc::c_int));
+    match config.keepalive_interval {
+        Some(secs) => try!(setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs as libc::c_int)),
+        None => {}
+    }
+    match config.keepalive_count {
+        Some(count) => try!(setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count as libc::c_int)),
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive_probes(_fd : RawFd, _idle : u32, _config : &SocketConfig) -> io::Result<()> {
+    // the probe timing is system wide here
+    Ok(())
+}
+
+/// Applies the `[service.socket]` options to an accepted or connected TCP socket.
+pub fn apply(fd : RawFd, config : &SocketConfig) -> io::Result<()> {
+    match config.nodelay {
+        Some(on) => try!(setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, on as libc::c_int)),
+        None => {}
+    }
+    match config.keepalive {
+        Some(idle) => {
+            try!(setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1));
+            try!(set_keepalive_probes(fd, idle, config));
+        }
+        None => {}
+    }
+    set_buffers(fd, config)
+}
@@ -1,12 +1,214 @@
-use std::io::{Result, ErrorKind, Write, Read, BufRead};
-use std::net::SocketAddr;
+use std::io;
+use std::io::{Result, Write, Read, BufRead};
 use std::cmp::min;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use mio::{Token, Evented, EventSet};
 use mio::tcp::{TcpStream, Shutdown};
+use mio::unix::UnixStream;
+use libc;
+use net2::TcpBuilder;
+use time;
+use time::Timespec;
 
 use super::buffer::Buffer;
 use super::looper::{Eventer, LOOPER};
 use super::bufwrite::BufWrite;
+use super::sim;
+use super::sim::SimStream;
+use super::addr::Addr;
+use super::tls::{TlsStream, Connector};
+use super::socket;
+use super::config::SocketConfig;
+
+fn connect_tcp(addr : &SocketAddr, options : &SocketConfig) -> Result<TcpStream> {
+    let builder = try!(match *addr {
+        SocketAddr::V4(..) => TcpBuilder::new_v4(),
+        SocketAddr::V6(..) => TcpBuilder::new_v6(),
+    });
+    match socket::apply(builder.as_raw_fd(), options) {
+        Ok(()) => {}
+        Err(e) => {
+            info!("socket options for {} err {:?}", addr, e);
+        }
+    }
+    TcpStream::connect_stream(try!(builder.to_tcp_stream()), addr)
+}
+
+pub enum Transport {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream),
+    Sim(SimStream),
+}
+
+impl Transport {
+    pub fn connect(addr : &Addr, tls : Option<&Connector>, options : &SocketConfig) -> Result<Transport> {
+        match *addr {
+            Addr::Tcp(ref a) => {
+                if sim::active() {
+                    Ok(Transport::Sim(SimStream::connect(a)))
+                } else {
+                    connect_tcp(a, options).map(Transport::Tcp)
+                }
+            }
+            Addr::Tls(ref a, ref host) => {
+                if sim::active() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "tls is not simulated"));
+                }
+                let connector = match tls {
+                    Some(connector) => connector,
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::Other, "no tls config"));
+                    }
+                };
+                let s = try!(connect_tcp(a, options));
+                TlsStream::connect(connector, s, host).map(Transport::Tls)
+            }
+            Addr::Unix(ref p) => {
+                if sim::active() {
+                    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not simulated"))
+                } else {
+                    UnixStream::connect(p).map(Transport::Unix)
+                }
+            }
+        }
+    }
+    pub fn shutdown(&mut self, how : Shutdown) -> Result<()> {
+        match *self {
+            Transport::Tcp(ref s) => s.shutdown(how),
+            Transport::Tls(ref mut s) => {
+                if how != Shutdown::Read {
+                    // a truncation attack looks like a plain TCP close, the alert tells them apart
+                    match s.close_notify() {
+                        Ok(()) => {}
+                        Err(e) => {
+                            trace!("tls close_notify err {:?}", e);
+                        }
+                    }
+                }
+                s.get_ref().shutdown(how)
+            }
+            Transport::Unix(ref s) => {
+                // mio's UnixStream has no shutdown of its own
+                let how = match how {
+                    Shutdown::Read => libc::SHUT_RD,
+                    Shutdown::Write => libc::SHUT_WR,
+                    Shutdown::Both => libc::SHUT_RDWR,
+                };
+                if unsafe { libc::shutdown(s.as_raw_fd(), how) } < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            }
+            Transport::Sim(ref s) => {
+                s.shutdown(how);
+                Ok(())
+            }
+        }
+    }
+    pub fn tcp_fd(&self) -> Option<RawFd> {
+        match *self {
+            Transport::Tcp(ref s) => Some(s.as_raw_fd()),
+            Transport::Tls(ref s) => Some(s.get_ref().as_raw_fd()),
+            Transport::Unix(_) => None,
+            Transport::Sim(_) => None,
+        }
+    }
+    pub fn take_error(&self) -> Option<io::Error> {
+        let fd = match *self {
+            Transport::Tcp(ref s) => s.as_raw_fd(),
+            Transport::Unix(ref s) => s.as_raw_fd(),
+            Transport::Tls(ref s) => s.get_ref().as_raw_fd(),
+            Transport::Sim(ref s) => {
+                return s.take_error();
+            }
+        };
+        match socket::take_error(fd) {
+            Ok(e) => e,
+            Err(e) => Some(e),
+        }
+    }
+    fn evented(&self) -> Option<&Evented> {
+        match *self {
+            Transport::Tcp(ref s) => Some(s),
+            Transport::Unix(ref s) => Some(s),
+            Transport::Tls(ref s) => Some(s.get_ref()),
+            Transport::Sim(_) => None,
+        }
+    }
+    fn sim_id(&self) -> Option<usize> {
+        match *self {
+            Transport::Tcp(_) => None,
+            Transport::Unix(_) => None,
+            Transport::Tls(_) => None,
+            Transport::Sim(ref s) => Some(s.id()),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.read(buf),
+            Transport::Unix(ref mut s) => s.read(buf),
+            Transport::Tls(ref mut s) => s.read(buf),
+            Transport::Sim(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.write(buf),
+            Transport::Unix(ref mut s) => s.write(buf),
+            Transport::Tls(ref mut s) => s.write(buf),
+            Transport::Sim(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.flush(),
+            Transport::Unix(ref mut s) => s.flush(),
+            Transport::Tls(ref mut s) => s.flush(),
+            Transport::Sim(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// What went wrong on a connection, see `ServiceHandler::on_error`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorKind {
+    Decode,
+    Encode,
+    Io(io::ErrorKind),
+    Hangup,
+}
+
+/// Why a connection went away, given to `ServiceHandler::disconnected`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisconnectReason {
+    Local,
+    Overflow,
+    Error(ErrorKind),
+}
+
+/// A snapshot of one connection, see `ServiceRef::connection_info`.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub token : Token,
+    pub peer_addr : Addr,
+    pub is_client : bool,
+    pub connected_at : Option<Timespec>,
+    pub bytes_read : u64,
+    pub bytes_written : u64,
+    pub packets_read : u64,
+    pub packets_written : u64,
+    pub rbuf_len : usize,
+    pub wbuf_len : usize,
+}
 
 pub struct Stream {
     token : Token,
@@ -16,8 +218,20 @@ pub struct Stream {
     pub is_client : bool,
     pub connecting : bool,
     pub reconnect : bool,
-    pub peer_addr : SocketAddr,
-    pub stream : TcpStream,
+    pub closing : bool,
+    pub peer_addr : Addr,
+    pub stream : Transport,
+    pub connected_at : Option<Timespec>,
+    pub reason : Option<DisconnectReason>,
+    pub skips : u32,
+    pub packets_read : u64,
+    pub packets_written : u64,
+    bytes_read : u64,
+    bytes_written : u64,
+    high : usize,
+    low : usize,
+    blocked : bool,
+    unblocked : bool,
     wbuf : Buffer,
     rbuf : Buffer,
 }
@@ -28,7 +242,7 @@ const MORE_WBUF_SIZE : usize = 4096;
 const MORE_RBUF_SIZE : usize = 4096;
 
 impl Stream {
-    pub fn new(token : Token, stream : TcpStream, is_client : bool, reconnect : bool, peer_addr : SocketAddr) -> Self {
+    pub fn new(token : Token, stream : Transport, is_client : bool, reconnect : bool, peer_addr : Addr) -> Self {
         Stream {
             token : token,
             registered : EventSet::none(),
@@ -37,16 +251,76 @@ impl Stream {
             is_client : is_client,
             connecting : true,
             reconnect : reconnect,
+            closing : false,
             peer_addr : peer_addr,
             stream : stream,
+            connected_at : None,
+            reason : None,
+            skips : 0,
+            packets_read : 0,
+            packets_written : 0,
+            bytes_read : 0,
+            bytes_written : 0,
+            high : 0,
+            low : 0,
+            blocked : false,
+            unblocked : false,
             wbuf : Buffer::with_capacity(INIT_WBUF_SIZE),
             rbuf : Buffer::with_capacity(INIT_RBUF_SIZE),
         }
     }
+    pub fn set_watermark(&mut self, high : usize, low : usize) {
+        self.high = high;
+        self.low = low;
+    }
+    pub fn over_high(&mut self) -> bool {
+        if self.high > 0 && self.wbuf.data_len() >= self.high {
+            self.blocked = true;
+        }
+        self.blocked
+    }
+    pub fn drained(&mut self) -> bool {
+        let unblocked = self.unblocked;
+        self.unblocked = false;
+        unblocked
+    }
+    fn check_low(&mut self) {
+        if self.blocked && self.wbuf.data_len() <= self.low {
+            self.blocked = false;
+            self.unblocked = true;
+        }
+    }
+    pub fn set_connected(&mut self) {
+        self.connecting = false;
+        self.connected_at = Some(time::get_time());
+    }
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            token : self.token,
+            peer_addr : self.peer_addr.clone(),
+            is_client : self.is_client,
+            connected_at : self.connected_at,
+            bytes_read : self.bytes_read,
+            bytes_written : self.bytes_written,
+            packets_read : self.packets_read,
+            packets_written : self.packets_written,
+            rbuf_len : self.rbuf.data_len(),
+            wbuf_len : self.wbuf.data_len(),
+        }
+    }
+    pub fn close(&mut self, reason : DisconnectReason) {
+        if self.reason.is_none() {
+            self.reason = Some(reason);
+        }
+        self.shutdown();
+    }
     pub fn shutdown(&mut self) {
         if self.interest == EventSet::none() {
             return;
         }
+        if self.reason.is_none() {
+            self.reason = Some(DisconnectReason::Local);
+        }
         self.got = EventSet::hup();
         self.stream.shutdown(Shutdown::Both).ok();
         trace!("stream shutdown");
@@ -55,6 +329,31 @@ impl Stream {
             looper.borrow_mut().as_mut().unwrap().reregister(self.token);
         });
     }
+    /// Half-close once everything already written has been sent, the peer then sees the end of the stream.
+    pub fn close_after_flush(&mut self) {
+        if self.closing {
+            return;
+        }
+        trace!("stream closing");
+        self.closing = true;
+        if self.reason.is_none() {
+            self.reason = Some(DisconnectReason::Local);
+        }
+        if self.wbuf.is_empty() {
+            self.half_close();
+        }
+    }
+    fn half_close(&mut self) {
+        trace!("stream half close");
+        match self.stream.shutdown(Shutdown::Write) {
+            Ok(_) => {
+            }
+            Err(e) => {
+                trace!("stream half close err {:?}", e);
+                self.shutdown();
+            }
+        }
+    }
     fn want_writable(&mut self) {
         self.got.remove(EventSet::writable());
     }
@@ -73,8 +372,11 @@ impl Eventer for Stream {
     fn interest(&self) -> EventSet {
         self.interest
     }
-    fn evented(&self) -> &Evented {
-        &self.stream
+    fn evented(&self) -> Option<&Evented> {
+        self.stream.evented()
+    }
+    fn sim_id(&self) -> Option<usize> {
+        self.stream.sim_id()
     }
 }
 
@@ -90,18 +392,22 @@ impl Write for Stream {
             trace!("stream flush");
             match self.stream.write(self.wbuf.fill_buf().unwrap()) {
                 Ok(part) => {
+                    self.bytes_written += part as u64;
                     self.wbuf.consume(part);
+                    self.check_low();
                     if !self.wbuf.is_empty() {
                         self.want_writable();
+                    } else if self.closing {
+                        self.half_close();
                     }
                     Ok(())
                 },
                 Err(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
+                    if e.kind() == io::ErrorKind::WouldBlock {
                         self.want_writable();
                     } else {
                         trace!("stream write err {:?}", e);
-                        self.shutdown();
+                        self.close(DisconnectReason::Error(ErrorKind::Io(e.kind())));
                     }
                     Err(e)
                 }
@@ -142,19 +448,20 @@ impl BufRead for Stream {
         loop {
             match self.stream.read(self.rbuf.reserve_buf(MORE_RBUF_SIZE)) {
                 Ok(part) => {
+                    self.bytes_read += part as u64;
                     self.rbuf.buf_filled(part);
                     if part == 0 {
                         trace!("stream read zero");
-                        self.shutdown();
+                        self.close(DisconnectReason::Error(ErrorKind::Hangup));
                         break;
                     }
                 },
                 Err(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
+                    if e.kind() == io::ErrorKind::WouldBlock {
                         self.want_readable();
                     } else {
                         trace!("stream read err {:?}", e);
-                        self.shutdown();
+                        self.close(DisconnectReason::Error(ErrorKind::Io(e.kind())));
                     }
                     if self.rbuf.is_empty() {
                         return Err(e)
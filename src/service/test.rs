@@ -38,18 +38,19 @@ impl ServiceStreamer for TestService {
 impl ServiceHandler for TestService {
     type Packet = u8;
     type Streamer = TestService;
+    type Session = ();
     fn connected(&self, token : Token) {
         service_write!(TEST_SERVICE, token, &1u8);
     }
-    fn disconnected(&self, token : Token) {
+    fn disconnected(&self, token : Token, _session : Self::Session, _reason : DisconnectReason) {
         service_exit!(TEST_SERVICE);
     }
-    fn incoming(&self, token : Token, packet : Self::Packet) {
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
         if packet == 1u8 {
             service_shutdown!(TEST_SERVICE, token);
         }
     }
-    fn outgoing(&self, token : Token, packet : &Self::Packet) {
+    fn outgoing(&self, token : Token, _session : Option<&mut Self::Session>, packet : &Self::Packet) {
     }
 }
 
@@ -60,9 +61,213 @@ fn service_test() {
         name : "test service".to_string(),
         listen : vec!["0.0.0.0:12306"].iter().map(|s| s.to_string()).collect(),
         connect : vec!["127.0.0.1:12306"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
     };
-    service_start!(TEST_SERVICE, TestService, conf);
+    service_start!(TEST_SERVICE, TestService, conf).unwrap();
     trace!("loop begin");
     run_loop();
     trace!("loop exit");
 }
+
+#[test]
+fn service_start_error() {
+    let conf = ServiceConfig::client("bad service", "no-such-host.invalid:12307");
+    match ServiceRef::new(TestService).start(conf) {
+        Err(ServiceError::ResolveError(name, addr, _)) => {
+            assert_eq!(name, "bad service");
+            assert_eq!(addr, "no-such-host.invalid:12307");
+        }
+        _ => panic!("resolve should fail"),
+    }
+    match ServiceConfig::from_file("no-such-config.toml", "bad service") {
+        Err(ServiceError::IoError(_)) => {}
+        _ => panic!("missing file should fail"),
+    }
+}
+
+#[test]
+fn service_start_bind_error() {
+    use std::net::TcpListener;
+    init();
+    let conf = ServiceConfig {
+        name : "bind twice".to_string(),
+        listen : vec!["127.0.0.1:12308", "127.0.0.1:12308"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:12308"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    match ServiceRef::new(TestService).start(conf) {
+        Err(ServiceError::BindError(name, _, _)) => assert_eq!(name, "bind twice"),
+        _ => panic!("the second bind should fail"),
+    }
+    // the first listener was let go and nothing was registered or connected
+    drop(TcpListener::bind("127.0.0.1:12308").unwrap());
+    run_loop();
+}
+
+#[test]
+fn tls_client_needs_ca() {
+    init();
+    let mut conf = ServiceConfig::client("tls without ca", "tls:127.0.0.1:12309");
+    conf.tls = Some(TlsConfig::default());
+    match ServiceRef::new(TestService).start(conf.clone()) {
+        Err(ServiceError::ConfigError(name, _)) => assert_eq!(name, "tls without ca"),
+        _ => panic!("a tls connection without a ca should be refused"),
+    }
+    // unless it is asked for
+    conf.tls = Some(TlsConfig { insecure : Some(true), ..Default::default() });
+    ServiceRef::new(TestService).start(conf).unwrap();
+}
+
+#[test]
+fn cidr_contains() {
+    use std::str::FromStr;
+    use std::net::IpAddr;
+    let ip = |s : &str| IpAddr::from_str(s).unwrap();
+    let app = Cidr::from_str("10.1.0.0/16").unwrap();
+    assert!(app.contains(&ip("10.1.2.3")));
+    assert!(!app.contains(&ip("10.2.0.1")));
+    assert!(!app.contains(&ip("::1")));
+    let odd = Cidr::from_str("192.168.1.128/25").unwrap();
+    assert!(odd.contains(&ip("192.168.1.200")));
+    assert!(!odd.contains(&ip("192.168.1.100")));
+    assert!(Cidr::from_str("fd00::/8").unwrap().contains(&ip("fd12::1")));
+    assert!(Cidr::from_str("127.0.0.1").unwrap().contains(&ip("127.0.0.1")));
+    assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+    // IPv4 peers of a dual-stack listener
+    assert!(app.contains(&ip("::ffff:10.1.2.3")));
+    assert!(!app.contains(&ip("::ffff:10.2.0.1")));
+    assert!(!app.contains(&ip("::10.1.2.3")));
+    assert!(Cidr::from_str("::ffff:10.1.0.0/112").unwrap().contains(&ip("10.1.2.3")));
+    assert!(Cidr::from_str("::ffff:10.1.0.0/112").unwrap().contains(&ip("::ffff:10.1.2.3")));
+    assert!(Cidr::from_str("10.0.0.0/33").is_err());
+    assert!(Cidr::from_str("app-subnet").is_err());
+}
+
+#[test]
+fn streamer_skippable() {
+    use streamer::json::JsonStreamer;
+    let mut buf = Vec::new();
+    buf.extend(b"[3]abc".iter().cloned());
+    JsonStreamer::<Vec<u32>>::write_packet(&vec![1, 2], &mut buf).unwrap();
+    let mut reader = &buf[..];
+    let e = JsonStreamer::<Vec<u32>>::read_packet(&mut reader).unwrap_err();
+    let len = JsonStreamer::<Vec<u32>>::skippable(&e).unwrap();
+    assert_eq!(len, 6);
+    reader.consume(len);
+    assert_eq!(JsonStreamer::<Vec<u32>>::read_packet(&mut reader).unwrap(), Some(vec![1, 2]));
+}
+
+#[test]
+fn socket_options() {
+    use std::mem;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use libc;
+    let getsockopt = |fd, level, name| {
+        let mut value : libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe {
+            libc::getsockopt(fd, level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len);
+        }
+        value
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let fd = stream.as_raw_fd();
+    let config = SocketConfig {
+        nodelay : Some(true),
+        keepalive : Some(60),
+        ..Default::default()
+    };
+    super::socket::apply(fd, &config).unwrap();
+    assert!(getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY) != 0);
+    assert!(getsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE) != 0);
+    // outbound sockets have them before they connect
+    let config = SocketConfig {
+        nodelay : Some(true),
+        recv_buffer : Some(200_000),
+        ..Default::default()
+    };
+    let to = Addr::Tcp(listener.local_addr().unwrap());
+    let transport = super::stream::Transport::connect(&to, None, &config).unwrap();
+    let fd = transport.tcp_fd().unwrap();
+    assert!(getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY) != 0);
+    assert!(getsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 200_000);
+}
+
+#[test]
+fn socket_options_range() {
+    init();
+    let bad = vec![
+        SocketConfig { keepalive : Some(0), ..Default::default() },
+        SocketConfig { keepalive : Some(60), keepalive_count : Some(0), ..Default::default() },
+        SocketConfig { keepalive_interval : Some(1 << 31), ..Default::default() },
+        SocketConfig { send_buffer : Some(1 << 31), ..Default::default() },
+        SocketConfig { backlog : Some(0), ..Default::default() },
+    ];
+    for socket in bad {
+        let mut conf = ServiceConfig::client("socket range", "127.0.0.1:12310");
+        conf.socket = Some(socket.clone());
+        match ServiceRef::new(TestService).start(conf) {
+            Err(ServiceError::ConfigError(name, _)) => assert_eq!(name, "socket range"),
+            _ => panic!("{:?} should be refused", socket),
+        }
+    }
+}
+
+#[test]
+fn reconnect_delay() {
+    use toml;
+    let mut parser = toml::Parser::new("[s]\nname = \"s\"\nlisten = []\nconnect = []\n[s.reconnect]\ninitial_delay = 100\nmultiplier = 2.0\nmax_delay = 1000\n");
+    let mut table = parser.parse().unwrap();
+    // keys left out of the section take their defaults
+    let reconnect = ServiceConfig::from_toml(table.remove("s").unwrap()).unwrap().reconnect.unwrap();
+    assert_eq!(reconnect.delay(1, 0.5), 100);
+    assert_eq!(reconnect.delay(2, 0.5), 200);
+    assert_eq!(reconnect.delay(4, 0.5), 800);
+    assert_eq!(reconnect.delay(5, 0.5), 1000);
+    assert_eq!(reconnect.delay(50, 0.5), 1000);
+    assert!(!reconnect.gave_up(1000));
+    let default = ReconnectConfig::default();
+    assert_eq!(default.delay(1, 0.0), 5_000);
+    assert_eq!(default.delay(10, 0.9), 5_000);
+    let jitter = ReconnectConfig { initial_delay : Some(1000), jitter : Some(0.5), ..Default::default() };
+    assert_eq!(jitter.delay(1, 0.0), 500);
+    assert_eq!(jitter.delay(1, 0.5), 1000);
+    assert_eq!(jitter.delay(1, 0.75), 1250);
+    let limited = ReconnectConfig { max_attempts : Some(3), ..Default::default() };
+    assert!(!limited.gave_up(2));
+    assert!(limited.gave_up(3));
+}
+
+#[test]
+fn unix_socket_file() {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use super::listen::Listener;
+    let path = Path::new("/tmp/ds_unix_socket_file.sock");
+    let addr = Addr::Unix(path.to_path_buf());
+    let options = SocketConfig::default();
+    fs::remove_file(path).ok();
+    // not a socket, left alone
+    fs::File::create(path).unwrap();
+    assert!(Listener::bind(&addr, None, &options).is_err());
+    assert!(fs::metadata(path).unwrap().is_file());
+    fs::remove_file(path).unwrap();
+    // a live listener keeps its path, and takes it along when it goes
+    let live = Listener::bind(&addr, None, &options).unwrap();
+    match Listener::bind(&addr, None, &options) {
+        Err(ref e) if e.kind() == ErrorKind::AddrInUse => {}
+        _ => panic!("a socket in use should not be taken over"),
+    }
+    // found in use without connecting to it
+    assert!(live.accept().unwrap().is_none());
+    drop(live);
+    assert!(fs::metadata(path).is_err());
+    // a socket nobody accepts on is replaced
+    drop(::mio::unix::UnixListener::bind(path).unwrap());
+    assert!(fs::metadata(path).is_ok());
+    drop(Listener::bind(&addr, None, &options).unwrap());
+    assert!(fs::metadata(path).is_err());
+}
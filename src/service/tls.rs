@@ -0,0 +1,124 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fmt::Display;
+use mio::tcp::TcpStream;
+use openssl::ssl::{Ssl, SslContext, SslMethod, NonblockingSslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::ssl::error::NonblockingSslError;
+use openssl::x509::X509FileType;
+use openssl_verify;
+
+use super::config::TlsConfig;
+use super::error::ServiceError;
+
+/// Builds the context `tls:` listeners (`server`) or connections of the service `name` use.
+/// A listener needs a certificate and key. A connection needs a CA to verify the server against,
+/// unless `insecure` says to take any server.
+pub fn context(name : &str, config : &TlsConfig, server : bool) -> Result<SslContext, ServiceError> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| config_error(name, "context", e)));
+    match (&config.cert, &config.key) {
+        (&Some(ref cert), &Some(ref key)) => {
+            try!(ctx.set_certificate_file(cert, X509FileType::PEM).map_err(|e| config_error(name, cert, e)));
+            try!(ctx.set_private_key_file(key, X509FileType::PEM).map_err(|e| config_error(name, key, e)));
+            try!(ctx.check_private_key().map_err(|e| config_error(name, "key", e)));
+        }
+        (&None, &None) if !server => {
+        }
+        _ => {
+            return Err(ServiceError::ConfigError(name.to_string(), "tls needs both cert and key".to_string()));
+        }
+    }
+    match config.ca {
+        Some(ref ca) => {
+            try!(ctx.set_CA_file(ca).map_err(|e| config_error(name, ca, e)));
+            if !server {
+                ctx.set_verify(SSL_VERIFY_PEER, None);
+            } else if config.verify_peer.unwrap_or(false) {
+                ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+            } else {
+                ctx.set_verify(SSL_VERIFY_NONE, None);
+            }
+        }
+        None => {
+            if server && config.verify_peer.unwrap_or(false) {
+                return Err(ServiceError::ConfigError(name.to_string(), "tls verify_peer needs a ca".to_string()));
+            }
+            if !server && !config.insecure.unwrap_or(false) {
+                return Err(ServiceError::ConfigError(name.to_string(), "tls connections need a ca, or insecure = true to skip verifying the server".to_string()));
+            }
+            ctx.set_verify(SSL_VERIFY_NONE, None);
+        }
+    }
+    Ok(ctx)
+}
+
+/// The client side TLS of a service, the server certificate is checked against the CA and the host connected to.
+pub struct Connector {
+    ctx : SslContext,
+    verify : bool,
+}
+
+pub fn connector(name : &str, config : &TlsConfig) -> Result<Connector, ServiceError> {
+    let ctx = try!(context(name, config, false));
+    Ok(Connector { ctx : ctx, verify : config.ca.is_some() })
+}
+
+fn config_error<E : Display>(name : &str, what : &str, e : E) -> ServiceError {
+    ServiceError::ConfigError(name.to_string(), format!("tls {}: {}", what, e))
+}
+
+/// A TCP stream with TLS on top, the handshake runs as part of the first reads and writes.
+pub struct TlsStream {
+    stream : NonblockingSslStream<TcpStream>,
+}
+
+impl TlsStream {
+    /// `host` is the name or ip the connection was made to, as written in the config.
+    pub fn connect(connector : &Connector, stream : TcpStream, host : &str) -> io::Result<TlsStream> {
+        let mut ssl = try!(Ssl::new(&connector.ctx).map_err(ssl_error));
+        // SNI, for servers with a certificate per name
+        try!(ssl.set_hostname(host).map_err(ssl_error));
+        if connector.verify {
+            let host = host.to_string();
+            ssl.set_verify_callback(SSL_VERIFY_PEER, move |ok, x509| openssl_verify::verify_callback(&host, ok, x509));
+        }
+        NonblockingSslStream::connect(ssl, stream).map(|s| TlsStream { stream : s }).map_err(ssl_error)
+    }
+    pub fn accept(ctx : &SslContext, stream : TcpStream) -> io::Result<TlsStream> {
+        NonblockingSslStream::accept(ctx, stream).map(|s| TlsStream { stream : s }).map_err(ssl_error)
+    }
+    pub fn get_ref(&self) -> &TcpStream {
+        self.stream.get_ref()
+    }
+    /// Sends close_notify so the peer can tell the end of the stream from a cut connection.
+    /// The socket is nonblocking, an alert that does not fit is not retried.
+    pub fn close_notify(&mut self) -> io::Result<()> {
+        self.stream.shutdown().map(|_| ()).map_err(ssl_error)
+    }
+}
+
+fn ssl_error<E : Display>(e : E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("tls {}", e))
+}
+
+fn nonblocking_error(e : NonblockingSslError) -> io::Error {
+    match e {
+        // the stream is polled for both, whichever the handshake waits on wakes it again
+        NonblockingSslError::WantRead | NonblockingSslError::WantWrite => io::Error::new(io::ErrorKind::WouldBlock, "tls want io"),
+        NonblockingSslError::SslError(e) => ssl_error(e),
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf).map_err(nonblocking_error)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf).map_err(nonblocking_error)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -26,6 +26,18 @@ pub trait ErrorMapper {
     fn error_from_io(e: io::Error) -> Self::Error;
 }
 
+/// The error of a head/body streamer. A body that fails to decode has a known length,
+/// so the frame can be skipped without losing track of the stream.
+///
+/// This is the `ServiceStreamer::Error` of every `StreamerImpl`, `JsonStreamer` and `PwStreamer` included.
+/// Before 0.2 it was the mapped error itself, code that matches on it now finds that error
+/// in `Fatal(e)` or `Body(e, len)`.
+#[derive(Debug)]
+pub enum FrameError<E> {
+    Fatal(E),
+    Body(E, usize),
+}
+
 pub trait StreamerImpl {
     type Head : HeadStreamer;
     type Body : BodyStreamer;
@@ -40,15 +52,21 @@ impl<T,H,B,E> ServiceStreamer for T
           E: ErrorMapper<HE=H::Error, BE=B::Error>
 {
     type Packet = B::Packet;
-    type Error = E::Error;
+    type Error = FrameError<E::Error>;
     fn write_packet(packet: &Self::Packet, writer: &mut Write) -> Result<(), Self::Error> {
         match B::write_to_vec(packet) {
             Ok(v) => {
-                try!(H::write_len(v.len(), writer as &mut Write).map_err(E::error_from_head));
-                try!(writer.write(&v).map_err(E::error_from_io));
+                try!(H::write_len(v.len(), writer as &mut Write).map_err(|e| FrameError::Fatal(E::error_from_head(e))));
+                try!(writer.write(&v).map_err(|e| FrameError::Fatal(E::error_from_io(e))));
                 Ok(())
             }
-            Err(e) => Err(E::error_from_body(e)),
+            Err(e) => Err(FrameError::Fatal(E::error_from_body(e))),
+        }
+    }
+    fn skippable(e : &Self::Error) -> Option<usize> {
+        match *e {
+            FrameError::Fatal(_) => None,
+            FrameError::Body(_, len) => Some(len),
         }
     }
     fn read_packet(reader: &mut BufRead) -> Result<Option<Self::Packet>, Self::Error> {
@@ -62,13 +80,13 @@ impl<T,H,B,E> ServiceStreamer for T
                         if buf.len() < len {
                             return Ok(None);
                         }
-                        p = try!(B::read_from_slice(&buf[header_len..len]).map_err(E::error_from_body));
+                        p = try!(B::read_from_slice(&buf[header_len..len]).map_err(|e| FrameError::Body(E::error_from_body(e), len)));
                     }
                     Ok(None) => {
                         return Ok(None);
                     }
                     Err(e) => {
-                        return Err(E::error_from_head(e));
+                        return Err(FrameError::Fatal(E::error_from_head(e)));
                     }
                 }
             }
@@ -76,7 +94,7 @@ impl<T,H,B,E> ServiceStreamer for T
                 if e.kind() == io::ErrorKind::WouldBlock {
                     return Ok(None);
                 } else {
-                    return Err(E::error_from_io(e));
+                    return Err(FrameError::Fatal(E::error_from_io(e)));
                 }
             }
         }
@@ -3,7 +3,8 @@ use std::io;
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
-    WrongLen,
+    /// the lengths in the header do not add up, the frame is that many bytes long
+    WrongLen(usize),
 }
 
 impl From<io::Error> for Error {
@@ -29,6 +29,12 @@ impl ServiceStreamer for MemcachedStreamer {
         try!(writer.write_all(&packet.value[..]));
         Ok(())
     }
+    fn skippable(e : &Self::Error) -> Option<usize> {
+        match *e {
+            Error::WrongLen(len) => Some(len),
+            _ => None,
+        }
+    }
     fn read_packet(reader: &mut BufRead) -> Result<Option<Self::Packet>, Self::Error> {
         let len: usize;
         let p: Packet;
@@ -59,7 +65,7 @@ impl ServiceStreamer for MemcachedStreamer {
                 let valueend = 24 + bodylen as usize;
                 if keyend > valueend {
                     trace!("keyend {} valueend {}", keyend, valueend);
-                    return Err(Error::WrongLen);
+                    return Err(Error::WrongLen(totallen));
                 }
                 let mut ext = Vec::new();
                 try!((&buf[24..extend]).read_to_end(&mut ext));
@@ -4,3 +4,5 @@ pub mod memcached;
 
 mod headbody;
 
+pub use self::headbody::FrameError;
+
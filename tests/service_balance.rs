@@ -0,0 +1,109 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, WriteStatus, Balance, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static CLIENTS : RefCell<Vec<Token>> = RefCell::new(Vec::new()));
+thread_local!(static CHECKED : RefCell<bool> = RefCell::new(false));
+
+fn send(balance : Balance) -> Token {
+    service_send_balanced!(TEST_SERVICE, &Packet{x:0}, balance).unwrap()
+}
+
+fn keys() -> Vec<String> {
+    (0..20).map(|k| format!("key{}", k)).collect()
+}
+
+fn check(mut clients : Vec<Token>) {
+    clients.sort();
+    let rr : Vec<Token> = (0..6).map(|_| send(Balance::RoundRobin)).collect();
+    // every peer once, then the same order again
+    let mut first = rr[..3].to_vec();
+    first.sort();
+    assert_eq!(first, clients);
+    assert_eq!(&rr[..3], &rr[3..]);
+    // nothing is left unsent in the simulation, the tie goes to the lowest token
+    assert_eq!(send(Balance::LeastOutstanding), clients[0]);
+    let hashed : Vec<Token> = keys().iter().map(|k| send(Balance::ConsistentHash(k.as_bytes()))).collect();
+    let again : Vec<Token> = keys().iter().map(|k| send(Balance::ConsistentHash(k.as_bytes()))).collect();
+    assert_eq!(hashed, again);
+    // only the keys of the peer that went away move
+    let gone = hashed[0];
+    service_abort!(TEST_SERVICE, gone);
+    let after : Vec<Token> = keys().iter().map(|k| send(Balance::ConsistentHash(k.as_bytes()))).collect();
+    for (before, after) in hashed.iter().zip(after.iter()) {
+        assert!(*after != gone);
+        if *before != gone {
+            assert_eq!(before, after);
+        }
+    }
+    CHECKED.with(|c| *c.borrow_mut() = true);
+    service_exit!(TEST_SERVICE);
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if !info.is_client {
+            return;
+        }
+        let clients = CLIENTS.with(|c| {
+            c.borrow_mut().push(token);
+            c.borrow().clone()
+        });
+        if clients.len() == 3 {
+            check(clients);
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_balance() {
+    let checked = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(11));
+        let addrs : Vec<String> = vec!["10.0.0.2:44948", "10.0.0.3:44948", "10.0.0.4:44948"].iter().map(|s| s.to_string()).collect();
+        let conf = ServiceConfig {
+            name : "service_balance".to_string(),
+            listen : addrs.clone(),
+            connect : addrs,
+            ..Default::default()
+        };
+        service_start!(TEST_SERVICE, TestService, conf).unwrap();
+        // still connecting, there is nothing to pick from
+        assert_eq!(service_send_balanced!(TEST_SERVICE, &Packet{x:0}, Balance::RoundRobin), Err(WriteStatus::NoConnection));
+        sim::run();
+        CHECKED.with(|c| *c.borrow())
+    }).join().unwrap();
+    assert!(checked);
+}
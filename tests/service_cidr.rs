@@ -0,0 +1,102 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate net2;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::Duration;
+use serde::{Serializer, Deserializer};
+use net2::TcpBuilder;
+
+use ds::service::{Token, Addr, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop, sender};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static CONNECTED : Cell<usize> = Cell::new(0));
+thread_local!(static ASKED : RefCell<Vec<Ipv4Addr>> = RefCell::new(Vec::new()));
+thread_local!(static KEPT : RefCell<Vec<bool>> = RefCell::new(Vec::new()));
+
+fn v4(addr : &Addr) -> Ipv4Addr {
+    match addr.ip() {
+        Some(IpAddr::V4(a)) => a,
+        Some(IpAddr::V6(a)) => a.to_ipv4().unwrap(),
+        None => panic!("{} has no ip", addr),
+    }
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+        CONNECTED.with(|c| c.set(c.get() + 1));
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn accept(&self, peer : Addr) -> bool {
+        let ip = v4(&peer);
+        ASKED.with(|a| a.borrow_mut().push(ip));
+        ip != Ipv4Addr::new(127, 0, 0, 3)
+    }
+}
+
+#[test]
+fn service_cidr() {
+    init();
+    // a dual-stack listener, IPv4 clients arrive as ::ffff:127.0.0.x
+    let mut conf = ServiceConfig::server("service_cidr", "[::]:44959");
+    conf.allow = Some(vec!["127.0.0.0/30".to_string()]);
+    conf.deny = Some(vec!["127.0.0.2".to_string()]);
+    service_start!(TEST_SERVICE, TestService, conf).unwrap();
+    let posted = sender();
+    let clients = thread::spawn(move || {
+        let streams : Vec<_> = (1..5).map(|n| {
+            let builder = TcpBuilder::new_v4().unwrap();
+            builder.bind(&*format!("127.0.0.{}:0", n)).unwrap();
+            builder.connect("127.0.0.1:44959").unwrap()
+        }).collect();
+        let mut kept = Vec::new();
+        for mut stream in streams {
+            stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            let mut buf = [0u8; 16];
+            match stream.read(&mut buf) {
+                Ok(0) => kept.push(false),
+                Ok(n) => panic!("read {} bytes", n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => kept.push(true),
+                Err(e) => panic!("read err {:?}", e),
+            }
+        }
+        posted.post(move || {
+            KEPT.with(|k| *k.borrow_mut() = kept);
+            service_exit!(TEST_SERVICE);
+        }).unwrap();
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    clients.join().unwrap();
+    // .2 is denied, .4 is outside the allowed block, the hook turns .3 away
+    assert_eq!(KEPT.with(|k| k.borrow().clone()), vec![true, false, false, false]);
+    assert_eq!(CONNECTED.with(|c| c.get()), 1);
+    // only what passed the lists reaches the hook
+    assert_eq!(ASKED.with(|a| a.borrow().clone()), vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 3)]);
+}
@@ -0,0 +1,194 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate time;
+
+use std::cell::{Cell, RefCell};
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ErrorKind, ServiceHandler, ServiceStreamer, ServiceRef, ServiceConfig, WriteStatus, init, run_loop};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+const PACKETS : usize = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    pad : Vec<u8>,
+}
+
+fn packet() -> Packet {
+    Packet { pad : vec![7;65536] }
+}
+
+fn frame_len() -> usize {
+    let mut frame = Vec::new();
+    JsonStreamer::<Packet>::write_packet(&packet(), &mut frame).unwrap();
+    frame.len()
+}
+
+thread_local!(static REASONS : RefCell<Vec<DisconnectReason>> = RefCell::new(Vec::new()));
+
+struct FlushService;
+service_define!(FLUSH_SERVICE : FlushService);
+
+impl ServiceHandler for FlushService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        // more than the socket takes at once, most of it is still queued when the close starts
+        for _ in 0..PACKETS {
+            assert_eq!(service_write!(FLUSH_SERVICE, token, &packet()), WriteStatus::Written);
+        }
+        service_close_after_flush!(FLUSH_SERVICE, token);
+        assert_eq!(service_write!(FLUSH_SERVICE, token, &packet()), WriteStatus::Closed);
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, reason : DisconnectReason) {
+        REASONS.with(|r| r.borrow_mut().push(reason));
+        service_exit!(FLUSH_SERVICE);
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_close_after_flush() {
+    init();
+    service_start!(FLUSH_SERVICE, FlushService, ServiceConfig::server("service_close_after_flush", "127.0.0.1:44967")).unwrap();
+    let peer = thread::spawn(|| {
+        let mut stream = TcpStream::connect("127.0.0.1:44967").unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf.len()
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    // the peer read everything and then the end of the stream
+    assert_eq!(peer.join().unwrap(), frame_len() * PACKETS);
+    assert_eq!(REASONS.with(|r| r.borrow().clone()), vec![DisconnectReason::Local]);
+}
+
+thread_local!(static ABORTED_AT : Cell<u64> = Cell::new(0));
+thread_local!(static CLOSED_AT : RefCell<Vec<(bool, u64, DisconnectReason)>> = RefCell::new(Vec::new()));
+
+struct AbortService;
+service_define!(ABORT_SERVICE : AbortService);
+
+impl ServiceHandler for AbortService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    /// outbound connection
+    type Session = bool;
+    fn connected(&self, token : Token) -> bool {
+        let info = ABORT_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if !info.is_client {
+            ABORTED_AT.with(|a| a.set(sim::now()));
+            service_abort!(ABORT_SERVICE, token);
+            assert_eq!(service_write!(ABORT_SERVICE, token, &packet()), WriteStatus::Closed);
+        }
+        info.is_client
+    }
+    fn disconnected(&self, _token : Token, is_client : Self::Session, reason : DisconnectReason) {
+        let n = CLOSED_AT.with(|c| {
+            c.borrow_mut().push((is_client, sim::now(), reason));
+            c.borrow().len()
+        });
+        if n == 2 {
+            service_exit!(ABORT_SERVICE);
+        }
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_close_abort() {
+    let (aborted, mut closed) = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(17));
+        let conf = ServiceConfig {
+            name : "service_close_abort".to_string(),
+            listen : vec!["10.0.0.2:44950"].iter().map(|s| s.to_string()).collect(),
+            connect : vec!["10.0.0.2:44950"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        service_start!(ABORT_SERVICE, AbortService, conf).unwrap();
+        sim::run();
+        (ABORTED_AT.with(|a| a.get()), CLOSED_AT.with(|c| c.borrow().clone()))
+    }).join().unwrap();
+    closed.sort_by(|a, b| a.0.cmp(&b.0));
+    // the aborting side is gone at once, the peer one trip later, no linger
+    assert_eq!(closed[0].0, false);
+    assert_eq!(closed[0].1, aborted);
+    assert_eq!(closed[0].2, DisconnectReason::Local);
+    assert_eq!(closed[1].0, true);
+    assert!(closed[1].1 - aborted <= 10);
+    assert_eq!(closed[1].2, DisconnectReason::Error(ErrorKind::Hangup));
+}
+
+thread_local!(static CLOSING_AT : Cell<u64> = Cell::new(0));
+thread_local!(static LINGERED : Cell<u64> = Cell::new(0));
+
+struct LingerService;
+service_define!(LINGER_SERVICE : LingerService);
+
+impl ServiceHandler for LingerService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        assert_eq!(service_write!(LINGER_SERVICE, token, &packet()), WriteStatus::Written);
+        CLOSING_AT.with(|c| c.set(time::precise_time_ns()));
+        service_close_after_flush!(LINGER_SERVICE, token);
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, reason : DisconnectReason) {
+        assert_eq!(reason, DisconnectReason::Local);
+        let elapsed = (time::precise_time_ns() - CLOSING_AT.with(|c| c.get())) / 1_000_000;
+        LINGERED.with(|l| l.set(elapsed));
+        service_exit!(LINGER_SERVICE);
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_close_linger() {
+    init();
+    let mut conf = ServiceConfig::server("service_close_linger", "127.0.0.1:44961");
+    conf.linger = Some(200);
+    service_start!(LINGER_SERVICE, LingerService, conf).unwrap();
+    let (done, wait) = mpsc::channel();
+    let peer = thread::spawn(move || {
+        let mut stream = TcpStream::connect("127.0.0.1:44961").unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        // read to the end but never close, only the linger timeout ends the connection
+        wait.recv().unwrap();
+        buf.len()
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    done.send(()).unwrap();
+    assert_eq!(peer.join().unwrap(), frame_len());
+    let lingered = LINGERED.with(|l| l.get());
+    assert!(lingered >= 200 && lingered < 5_000, "lingered {}", lingered);
+}
@@ -0,0 +1,64 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{DatagramRef, DatagramHandler, WriteStatus, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct TestService {
+    recv : RefCell<i32>,
+}
+datagram_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        assert_eq!(*self.recv.borrow(), 3);
+    }
+}
+
+impl DatagramHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    fn incoming(&self, peer : SocketAddr, packet : Self::Packet) {
+        *self.recv.borrow_mut() += 1;
+        assert_eq!(packet.x, *self.recv.borrow());
+        if packet.x < 3 {
+            // the socket sends to itself, the peer is its own address
+            assert_eq!(datagram_send_to!(TEST_SERVICE, peer, &Packet{x:packet.x+1}), WriteStatus::Written);
+        } else {
+            datagram_exit!(TEST_SERVICE);
+        }
+    }
+}
+
+#[test]
+fn service_datagram() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_datagram".to_string(),
+        listen : vec!["127.0.0.1:44949".to_string()],
+        ..Default::default()
+    };
+    datagram_start!(TEST_SERVICE, TestService { recv : RefCell::new(0) }, conf).unwrap();
+    let to = SocketAddr::from_str("127.0.0.1:44949").unwrap();
+    assert_eq!(datagram_send_to!(TEST_SERVICE, to, &Packet{x:1}), WriteStatus::Written);
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
@@ -0,0 +1,106 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ErrorKind, ServiceHandler, ServiceRef, ServiceConfig, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static RESET : Cell<bool> = Cell::new(false));
+thread_local!(static CLIENTS : RefCell<HashSet<Token>> = RefCell::new(HashSet::new()));
+thread_local!(static LOG : RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+fn side(token : Token) -> &'static str {
+    if CLIENTS.with(|c| c.borrow().contains(&token)) { "client" } else { "server" }
+}
+
+fn log(s : String) {
+    LOG.with(|l| l.borrow_mut().push(s));
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            CLIENTS.with(|c| c.borrow_mut().insert(token));
+            if RESET.with(|r| r.get()) {
+                sim::disconnect(token);
+            }
+        } else if !RESET.with(|r| r.get()) {
+            TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().close_after_flush(token));
+        }
+    }
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        log(format!("disconnected {} {:?}", side(token), reason));
+        if LOG.with(|l| l.borrow().iter().filter(|l| l.starts_with("disconnected")).count()) == 2 {
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn on_error(&self, token : Token, kind : ErrorKind) {
+        log(format!("error {} {:?}", side(token), kind));
+    }
+}
+
+fn run(reset : bool) -> Vec<String> {
+    let mut log = thread::spawn(move || {
+        init();
+        RESET.with(|r| r.set(reset));
+        sim::start(SimConfig::new(13));
+        let conf = ServiceConfig {
+            name : "service_error".to_string(),
+            listen : vec!["10.0.0.2:44949"].iter().map(|s| s.to_string()).collect(),
+            connect : vec!["10.0.0.2:44949"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        service_start!(TEST_SERVICE, TestService, conf).unwrap();
+        sim::run();
+        LOG.with(|l| l.borrow().clone())
+    }).join().unwrap();
+    log.sort();
+    log
+}
+
+#[test]
+fn service_error_close() {
+    // an orderly close is no error on either side
+    assert_eq!(run(false), vec![
+        "disconnected client Error(Hangup)".to_string(),
+        "disconnected server Local".to_string(),
+    ]);
+}
+
+#[test]
+fn service_error_reset() {
+    assert_eq!(run(true), vec![
+        "disconnected client Error(Io(ConnectionReset))".to_string(),
+        "disconnected server Error(Io(ConnectionReset))".to_string(),
+        "error client Io(ConnectionReset)".to_string(),
+        "error server Io(ConnectionReset)".to_string(),
+    ]);
+}
@@ -0,0 +1,156 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, PacketFilter, Filtered, WriteStatus, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static DROPPED : Cell<i32> = Cell::new(0));
+
+struct Filter;
+
+impl PacketFilter<Packet> for Filter {
+    fn incoming(&mut self, _token : Token, packet : &Packet) -> Filtered<Packet> {
+        if packet.x < 0 {
+            DROPPED.with(|d| d.set(d.get() + 1));
+            Filtered::Drop
+        } else if packet.x == 0 {
+            Filtered::Reply(Packet{x:100})
+        } else {
+            Filtered::Pass
+        }
+    }
+    fn outgoing(&mut self, _token : Token, packet : &Packet) -> Filtered<Packet> {
+        if packet.x == 2 {
+            Filtered::Replace(Packet{x:20})
+        } else {
+            Filtered::Pass
+        }
+    }
+}
+
+struct TestService {
+    seen : RefCell<Vec<i32>>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        let mut seen = self.seen.borrow().clone();
+        seen.sort();
+        assert_eq!(seen, vec![1, 20, 100]);
+        assert_eq!(DROPPED.with(|d| d.get()), 1);
+    }
+}
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            for x in vec![-1, 0, 1, 2] {
+                service_write!(TEST_SERVICE, token, &Packet{x:x});
+            }
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        self.seen.borrow_mut().push(packet.x);
+        if self.seen.borrow().len() == 3 {
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_filter() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_filter".to_string(),
+        listen : vec!["0.0.0.0:44954"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44954"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { seen : RefCell::new(Vec::new()) }, conf).unwrap();
+    service_filter!(TEST_SERVICE, Filter);
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
+
+thread_local!(static INNER : RefCell<Vec<WriteStatus>> = RefCell::new(Vec::new()));
+
+/// Writes to its own service on the way in and out.
+struct WritingFilter;
+
+impl PacketFilter<Packet> for WritingFilter {
+    fn incoming(&mut self, token : Token, _packet : &Packet) -> Filtered<Packet> {
+        let status = service_write!(WRITE_SERVICE, token, &Packet{x:7});
+        INNER.with(|i| i.borrow_mut().push(status));
+        Filtered::Pass
+    }
+    fn outgoing(&mut self, token : Token, _packet : &Packet) -> Filtered<Packet> {
+        let status = service_write!(WRITE_SERVICE, token, &Packet{x:7});
+        INNER.with(|i| i.borrow_mut().push(status));
+        Filtered::Pass
+    }
+}
+
+struct WriteService;
+service_define!(WRITE_SERVICE : WriteService);
+
+impl ServiceHandler for WriteService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = WRITE_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            assert_eq!(service_write!(WRITE_SERVICE, token, &Packet{x:1}), WriteStatus::Written);
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        assert_eq!(packet.x, 1);
+        service_exit!(WRITE_SERVICE);
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_filter_writes() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_filter_writes".to_string(),
+        listen : vec!["0.0.0.0:44973"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44973"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    service_start!(WRITE_SERVICE, WriteService, conf).unwrap();
+    service_filter!(WRITE_SERVICE, WritingFilter);
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    // neither write got through, nor panicked
+    assert_eq!(INNER.with(|i| i.borrow().clone()), vec![WriteStatus::Dropped, WriteStatus::Dropped]);
+}
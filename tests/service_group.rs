@@ -0,0 +1,82 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    room : String,
+}
+
+struct Stat {
+    sent : i32,
+    recv : i32,
+}
+
+impl Drop for Stat {
+    fn drop(&mut self) {
+        assert_eq!(self.sent, 2);
+        assert_eq!(self.recv, 2);
+    }
+}
+
+struct TestService {
+    stat : RefCell<Stat>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            return;
+        }
+        service_join!(TEST_SERVICE, "room", token);
+        let members = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().members("room"));
+        if members.len() == 3 {
+            service_leave!(TEST_SERVICE, "room", token);
+            service_multicast!(TEST_SERVICE, "room", &Packet{room:"room".to_string()});
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        assert_eq!(packet.room, "room");
+        self.stat.borrow_mut().recv += 1;
+        if self.stat.borrow().recv == 2 {
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+        self.stat.borrow_mut().sent += 1;
+    }
+}
+
+#[test]
+fn service_group() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_group".to_string(),
+        listen : vec!["0.0.0.0:44948"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44948", "127.0.0.1:44948", "127.0.0.1:44948"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { stat : RefCell::new(Stat { sent : 0, recv : 0 }) }, conf).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
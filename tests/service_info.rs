@@ -0,0 +1,90 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::Cell;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, ConnectionInfo, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+const PACKETS : i32 = 3;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static CHECKED : Cell<usize> = Cell::new(0));
+
+fn info_of(token : Token) -> ConnectionInfo {
+    TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap()
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        if info_of(token).is_client {
+            for x in 0..PACKETS {
+                service_write!(TEST_SERVICE, token, &Packet{x:x});
+            }
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        let info = info_of(token);
+        assert!(info.connected_at.is_some());
+        assert!(info.bytes_read > 0);
+        if info.is_client {
+            assert_eq!(info.peer_addr.to_string(), "10.0.0.5:44970");
+            assert_eq!(info.packets_read, 1);
+            assert_eq!(info.packets_written, PACKETS as u64);
+            assert!(info.bytes_written > 0);
+            CHECKED.with(|c| c.set(c.get() + 1));
+            service_exit!(TEST_SERVICE);
+        } else if packet.x == PACKETS - 1 {
+            assert_eq!(info.packets_read, PACKETS as u64);
+            assert_eq!(info.packets_written, 0);
+            // the accepted end and the connecting one
+            let all : Vec<ConnectionInfo> = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connections()).collect();
+            assert_eq!(all.len(), 2);
+            assert_eq!(all.iter().filter(|i| i.is_client).count(), 1);
+            CHECKED.with(|c| c.set(c.get() + 1));
+            service_write!(TEST_SERVICE, token, &Packet{x:0});
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_info() {
+    let checked = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(7));
+        let conf = ServiceConfig {
+            name : "service_info".to_string(),
+            listen : vec!["10.0.0.5:44970"].iter().map(|s| s.to_string()).collect(),
+            connect : vec!["10.0.0.5:44970"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        service_start!(TEST_SERVICE, TestService, conf).unwrap();
+        sim::run();
+        CHECKED.with(|c| c.get())
+    }).join().unwrap();
+    assert_eq!(checked, 2);
+}
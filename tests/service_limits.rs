@@ -0,0 +1,89 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, LimitsConfig, init, run_loop, sender};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static CONNECTED : Cell<usize> = Cell::new(0));
+thread_local!(static CLOSED : Cell<usize> = Cell::new(0));
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+        CONNECTED.with(|c| c.set(c.get() + 1));
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+/// Opens one connection more than `limits` let in, returns how many the service kept and how many it closed.
+fn run(port : u16, limits : LimitsConfig) -> (usize, usize) {
+    init();
+    let mut conf = ServiceConfig::server("service_limits", &*format!("127.0.0.1:{}", port));
+    conf.limits = Some(limits);
+    service_start!(TEST_SERVICE, TestService, conf).unwrap();
+    let posted = sender();
+    let clients = thread::spawn(move || {
+        let streams : Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap()).collect();
+        let mut closed = 0;
+        for mut stream in streams {
+            stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            let mut buf = [0u8; 16];
+            match stream.read(&mut buf) {
+                Ok(0) => closed += 1,
+                Ok(n) => panic!("read {} bytes", n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("read err {:?}", e),
+            }
+        }
+        posted.post(move || {
+            CLOSED.with(|c| c.set(closed));
+            service_exit!(TEST_SERVICE);
+        }).unwrap();
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    clients.join().unwrap();
+    (CONNECTED.with(|c| c.get()), CLOSED.with(|c| c.get()))
+}
+
+#[test]
+fn service_limits_max_connections() {
+    // one accept per tick, the third connection still gets picked up and turned away
+    let limits = LimitsConfig { max_connections : Some(2), max_accepts_per_tick : Some(1), ..Default::default() };
+    assert_eq!(run(44957, limits), (2, 1));
+}
+
+#[test]
+fn service_limits_max_per_ip() {
+    let limits = LimitsConfig { max_per_ip : Some(2), ..Default::default() };
+    assert_eq!(run(44958, limits), (2, 1));
+}
@@ -13,7 +13,7 @@ use std::num::ParseIntError;
 
 use serde::{Serializer, Deserializer};
 
-use ds::service::{Token, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
 use ds::streamer::memcached::MemcachedStreamer;
 use ds::streamer::memcached;
 
@@ -24,11 +24,12 @@ service_define!(DB_SERVICE : DbService);
 impl ServiceHandler for DbService {
     type Packet = memcached::protocol::Packet;
     type Streamer = MemcachedStreamer;
+    type Session = ();
     fn connected(&self, _token : Token) {
     }
-    fn disconnected(&self, _token : Token) {
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
     }
-    fn incoming(&self, _token : Token, packet : Self::Packet) {
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
         trace!("incoming {:?}", packet);
         match packet.header.opcode {
             memcached::protocol::PROTOCOL_BINARY_CMD_GET => {
@@ -46,7 +47,7 @@ impl ServiceHandler for DbService {
             }
         }
     }
-    fn outgoing(&self, _token : Token, _packet : &Self::Packet) {
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
     }
 }
 
@@ -54,7 +55,7 @@ impl ServiceHandler for DbService {
 fn service_memcached() {
     init();
     let db_service = DbService;
-    service_start!(DB_SERVICE, db_service, ServiceConfig::client("db_service", "0.0.0.0:11211"));
+    service_start!(DB_SERVICE, db_service, ServiceConfig::client("db_service", "0.0.0.0:11211")).unwrap();
     service_broadcast!(DB_SERVICE, &memcached::protocol::Packet::new_request_set(0, "@@aaa.123".to_string(), b"123a123".to_vec()));
     run_loop();
 }
@@ -0,0 +1,134 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop, receiver, sender};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct TestService {
+    started : RefCell<bool>,
+    recv : RefCell<i32>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        assert_eq!(*self.recv.borrow(), 2);
+    }
+}
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        // the first end to connect hands its token to a background thread,
+        // which talks back to the loop through a typed message and a closure.
+        if *self.started.borrow() {
+            return;
+        }
+        *self.started.borrow_mut() = true;
+        let typed = receiver(|(token, x) : (Token, i32)| {
+            service_write!(TEST_SERVICE, token, &Packet{x:x});
+        });
+        let posted = sender();
+        thread::spawn(move || {
+            typed.send((token, 1)).unwrap();
+            posted.post(move || {
+                service_write!(TEST_SERVICE, token, &Packet{x:2});
+            }).unwrap();
+        });
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+        service_exit!(TEST_SERVICE);
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        *self.recv.borrow_mut() += 1;
+        if packet.x == 2 {
+            service_shutdown!(TEST_SERVICE, token);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_message() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_message".to_string(),
+        listen : vec!["0.0.0.0:44945"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44945"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { started : RefCell::new(false), recv : RefCell::new(0) }, conf).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
+
+thread_local!(static GONE : Cell<bool> = Cell::new(false));
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        GONE.with(|g| g.set(true));
+    }
+}
+
+struct ReceiverService;
+service_define!(RECEIVER_SERVICE : ReceiverService);
+
+impl ServiceHandler for ReceiverService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_message_receiver_gone() {
+    init();
+    service_start!(RECEIVER_SERVICE, ReceiverService, ServiceConfig::server("service_message_receiver_gone", "127.0.0.1:44971")).unwrap();
+    // the last sender goes, and the receiver with it
+    let guard = Guard;
+    let dropped = receiver(move |_ : i32| {
+        let _ = &guard;
+    });
+    drop(dropped);
+    // the service goes, and its receiver with it
+    let kept = service_receiver!(RECEIVER_SERVICE, |x : i32| {
+        panic!("{} reached a removed receiver", x);
+    });
+    service_timer!(RECEIVER_SERVICE, 50, move |_ : &ReceiverService| {
+        service_exit!(RECEIVER_SERVICE);
+        assert!(kept.send(1).is_err());
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    assert!(GONE.with(|g| g.get()));
+}
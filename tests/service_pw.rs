@@ -11,7 +11,7 @@ use std::cell::RefCell;
 use std::io::Write;
 use serde::{Serializer, Deserializer};
 
-use ds::service::{Token, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
 use ds::streamer::pw::PwStreamer;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -70,17 +70,18 @@ impl TestService {
 impl ServiceHandler for TestService {
     type Packet = ProtocolFrom1;
     type Streamer = PwStreamer<Self::Packet>;
+    type Session = ();
     fn connected(&self, token : Token) {
         self.stat.borrow_mut().conn += 1;
         if self.stat.borrow().send == 0 {
             service_write!(TEST_SERVICE, token, &ProtocolFrom1::Proto1(Packet{x:1,y:1,zzz:vec![0x21;256]}));
         }
     }
-    fn disconnected(&self, token : Token) {
+    fn disconnected(&self, token : Token, _session : Self::Session, _reason : DisconnectReason) {
         self.stat.borrow_mut().disc += 1;
         service_exit!(TEST_SERVICE);
     }
-    fn incoming(&self, token : Token, packett : Self::Packet) {
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packett : Self::Packet) {
         self.stat.borrow_mut().recv += 1;
         let ProtocolFrom1::Proto1(packet) = packett;
         assert!(packet.x == 1);
@@ -90,7 +91,7 @@ impl ServiceHandler for TestService {
             service_shutdown!(TEST_SERVICE, token);
         }
     }
-    fn outgoing(&self, token : Token, packet : &Self::Packet) {
+    fn outgoing(&self, token : Token, _session : Option<&mut Self::Session>, packet : &Self::Packet) {
         self.stat.borrow_mut().send += 1;
     }
 }
@@ -102,8 +103,9 @@ fn service_pw() {
         name : "service_pw".to_string(),
         listen : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
         connect : vec!["127.0.0.1:44944"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
     };
-    service_start!(TEST_SERVICE, TestService::new(), conf);
+    service_start!(TEST_SERVICE, TestService::new(), conf).unwrap();
     trace!("loop begin");
     run_loop();
     trace!("loop exit");
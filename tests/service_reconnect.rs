@@ -0,0 +1,167 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, Addr, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, ReconnectConfig, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static LOG : RefCell<Vec<(u64, String)>> = RefCell::new(Vec::new()));
+
+fn log(s : String) {
+    LOG.with(|l| l.borrow_mut().push((sim::now(), s)));
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        panic!("nothing listens, {:?} can not connect", token);
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn connect_failed(&self, addr : Addr, attempts : u32) {
+        log(format!("failed {} {}", addr, attempts));
+    }
+    fn connect_gave_up(&self, addr : Addr) {
+        log(format!("gave up {}", addr));
+    }
+}
+
+#[test]
+fn service_reconnect() {
+    let log = thread::spawn(|| {
+        init();
+        let mut config = SimConfig::new(3);
+        config.latency_min = 5;
+        config.latency_max = 5;
+        sim::start(config);
+        let mut conf = ServiceConfig::client("service_reconnect", "10.0.0.9:44945");
+        conf.reconnect = Some(ReconnectConfig {
+            initial_delay : Some(100),
+            multiplier : Some(2.0),
+            max_attempts : Some(3),
+            ..Default::default()
+        });
+        service_start!(TEST_SERVICE, TestService, conf).unwrap();
+        // after giving up nothing is left to run
+        sim::run();
+        LOG.with(|l| l.borrow().clone())
+    }).join().unwrap();
+    trace!("reconnect log {:?}", log);
+    let events : Vec<&str> = log.iter().map(|&(_, ref s)| &s[..]).collect();
+    assert_eq!(events, vec![
+        "failed 10.0.0.9:44945 1",
+        "failed 10.0.0.9:44945 2",
+        "failed 10.0.0.9:44945 3",
+        "gave up 10.0.0.9:44945",
+    ]);
+    // the retries wait the configured backoff, 100ms then 200ms, plus the round trip of the refusal
+    let first = log[1].0 - log[0].0;
+    let second = log[2].0 - log[1].0;
+    assert!(first >= 100 && first < 200, "first retry after {}", first);
+    assert!(second >= 200 && second < 300, "second retry after {}", second);
+    assert_eq!(log[3].0, log[2].0);
+}
+
+thread_local!(static LISTEN : Cell<Option<Token>> = Cell::new(None));
+thread_local!(static CLIENT : Cell<Option<Token>> = Cell::new(None));
+
+struct DropService;
+service_define!(DROP_SERVICE : DropService);
+
+impl ServiceHandler for DropService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = DROP_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            log("connected".to_string());
+            CLIENT.with(|c| c.set(Some(token)));
+            // nothing takes the retries after the drop
+            service_unlisten!(DROP_SERVICE, LISTEN.with(|l| l.get()).unwrap());
+            sim::disconnect(token);
+        }
+    }
+    fn disconnected(&self, token : Token, _session : Self::Session, _reason : DisconnectReason) {
+        if CLIENT.with(|c| c.get()) == Some(token) {
+            log("dropped".to_string());
+        }
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn connect_failed(&self, addr : Addr, attempts : u32) {
+        log(format!("failed {} {}", addr, attempts));
+    }
+    fn connect_gave_up(&self, addr : Addr) {
+        log(format!("gave up {}", addr));
+    }
+}
+
+#[test]
+fn service_reconnect_after_drop() {
+    let log = thread::spawn(|| {
+        init();
+        let mut config = SimConfig::new(3);
+        config.latency_min = 5;
+        config.latency_max = 5;
+        sim::start(config);
+        let mut conf = ServiceConfig::client("service_reconnect_after_drop", "10.0.0.3:44945");
+        // the service wide policy would wait far longer
+        conf.reconnect = Some(ReconnectConfig { initial_delay : Some(10_000), ..Default::default() });
+        let mut policies = HashMap::new();
+        policies.insert("10.0.0.3:44945".to_string(), ReconnectConfig {
+            initial_delay : Some(100),
+            multiplier : Some(2.0),
+            max_attempts : Some(2),
+            ..Default::default()
+        });
+        conf.reconnect_to = Some(policies);
+        service_start!(DROP_SERVICE, DropService, conf).unwrap();
+        let listen = service_add_listen!(DROP_SERVICE, "10.0.0.3:44945").unwrap();
+        LISTEN.with(|l| l.set(Some(listen)));
+        sim::run();
+        LOG.with(|l| l.borrow().clone())
+    }).join().unwrap();
+    trace!("reconnect after drop log {:?}", log);
+    let events : Vec<&str> = log.iter().map(|&(_, ref s)| &s[..]).collect();
+    assert_eq!(events, vec![
+        "connected",
+        "dropped",
+        "failed 10.0.0.3:44945 1",
+        "failed 10.0.0.3:44945 2",
+        "gave up 10.0.0.3:44945",
+    ]);
+    // the backoff grows from the first retry on
+    let first = log[2].0 - log[1].0;
+    let second = log[3].0 - log[2].0;
+    assert!(first >= 100 && first < 200, "first retry after {}", first);
+    assert!(second >= 200 && second < 300, "second retry after {}", second);
+}
@@ -0,0 +1,189 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, Addr, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, ReconnectConfig, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+const ADDR : &'static str = "10.0.0.2:44946";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static LOG : RefCell<Vec<(u64, String)>> = RefCell::new(Vec::new()));
+
+fn log(s : String) {
+    LOG.with(|l| l.borrow_mut().push((sim::now(), s)));
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            log("connected".to_string());
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn listen_down(&self, addr : Addr) {
+        log(format!("down {}", addr));
+    }
+    fn listen_up(&self, addr : Addr) {
+        log(format!("up {}", addr));
+        // the new listener takes connections
+        service_add_connect!(TEST_SERVICE, ADDR).unwrap();
+    }
+}
+
+#[test]
+fn service_relisten() {
+    let log = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(5));
+        let conf = ServiceConfig {
+            name : "service_relisten".to_string(),
+            relisten : Some(ReconnectConfig { initial_delay : Some(50), ..Default::default() }),
+            ..Default::default()
+        };
+        service_start!(TEST_SERVICE, TestService, conf).unwrap();
+        let listen = service_add_listen!(TEST_SERVICE, ADDR).unwrap();
+        // the listener is only known to the simulation once the loop registered it
+        service_timer!(TEST_SERVICE, 10, move |_ : &TestService| {
+            log("fail".to_string());
+            sim::disconnect(listen);
+        });
+        sim::run();
+        LOG.with(|l| l.borrow().clone())
+    }).join().unwrap();
+    trace!("relisten log {:?}", log);
+    let events : Vec<&str> = log.iter().map(|&(_, ref s)| &s[..]).collect();
+    assert_eq!(events, vec!["fail", "down 10.0.0.2:44946", "up 10.0.0.2:44946", "connected"]);
+    // rebound after the relisten delay
+    assert!(log[2].0 - log[1].0 >= 50);
+}
+
+thread_local!(static REMOVED : Cell<Option<bool>> = Cell::new(None));
+
+struct RemoveService;
+service_define!(REMOVE_SERVICE : RemoveService);
+
+impl ServiceHandler for RemoveService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn listen_down(&self, _addr : Addr) {
+        // the listener is gone already, only the pending rebind is left to cancel
+        REMOVED.with(|r| r.set(Some(service_remove_listen!(REMOVE_SERVICE, "10.0.0.3:44946").unwrap())));
+    }
+    fn listen_up(&self, addr : Addr) {
+        panic!("{} was removed", addr);
+    }
+}
+
+#[test]
+fn service_relisten_remove() {
+    let removed = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(5));
+        let conf = ServiceConfig {
+            name : "service_relisten_remove".to_string(),
+            relisten : Some(ReconnectConfig { initial_delay : Some(50), ..Default::default() }),
+            ..Default::default()
+        };
+        service_start!(REMOVE_SERVICE, RemoveService, conf).unwrap();
+        let listen = service_add_listen!(REMOVE_SERVICE, "10.0.0.3:44946").unwrap();
+        service_timer!(REMOVE_SERVICE, 10, move |_ : &RemoveService| {
+            sim::disconnect(listen);
+        });
+        // nothing is left to wake the loop once the rebind is cancelled
+        sim::run();
+        // and there is nothing left to remove
+        assert_eq!(service_remove_listen!(REMOVE_SERVICE, "10.0.0.3:44946").unwrap(), false);
+        REMOVED.with(|r| r.get())
+    }).join().unwrap();
+    assert_eq!(removed, Some(true));
+}
+
+thread_local!(static GAVE_UP : RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+struct GiveUpService;
+service_define!(GIVE_UP_SERVICE : GiveUpService);
+
+impl ServiceHandler for GiveUpService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn listen_down(&self, _addr : Addr) {
+        // somebody else takes the address before the rebind
+        service_timer!(GIVE_UP_SERVICE, 10, |_ : &GiveUpService| {
+            service_add_listen!(GIVE_UP_SERVICE, "10.0.0.4:44946").unwrap();
+        });
+    }
+    fn listen_up(&self, addr : Addr) {
+        panic!("{} is taken", addr);
+    }
+    fn listen_gave_up(&self, addr : Addr) {
+        GAVE_UP.with(|g| g.borrow_mut().push(addr.to_string()));
+    }
+}
+
+#[test]
+fn service_relisten_gave_up() {
+    let gave_up = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(5));
+        let conf = ServiceConfig {
+            name : "service_relisten_gave_up".to_string(),
+            relisten : Some(ReconnectConfig { initial_delay : Some(50), max_attempts : Some(1), ..Default::default() }),
+            ..Default::default()
+        };
+        service_start!(GIVE_UP_SERVICE, GiveUpService, conf).unwrap();
+        let listen = service_add_listen!(GIVE_UP_SERVICE, "10.0.0.4:44946").unwrap();
+        service_timer!(GIVE_UP_SERVICE, 10, move |_ : &GiveUpService| {
+            sim::disconnect(listen);
+        });
+        sim::run();
+        GAVE_UP.with(|g| g.borrow().clone())
+    }).join().unwrap();
+    assert_eq!(gave_up, vec!["10.0.0.4:44946".to_string()]);
+}
@@ -0,0 +1,192 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate toml;
+extern crate libc;
+
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop, shutdown, load_config, reload};
+use ds::streamer::json::JsonStreamer;
+
+const CONFIG : &'static str = "/tmp/ds_service_reload.toml";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct TestService {
+    custom : RefCell<Option<i64>>,
+    conn : RefCell<i32>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        assert_eq!(*self.custom.borrow(), Some(2));
+        assert!(*self.conn.borrow() > 0);
+    }
+}
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            // only the reloaded config connects, to the listener it added
+            *self.conn.borrow_mut() += 1;
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn reconfigured(&self, changed : &toml::Table) {
+        assert!(!changed.contains_key("reload_service"));
+        let x = changed.get("custom").and_then(|c| c.lookup("x")).and_then(|x| x.as_integer());
+        *self.custom.borrow_mut() = x;
+    }
+}
+
+fn write_config(path : &str, section : &str, port : u16, connect : bool, x : i32) {
+    let connect = if connect { format!("\"127.0.0.1:{}\"", port) } else { String::new() };
+    let mut file = File::create(path).unwrap();
+    write!(file, "[{}]\nname = \"{}\"\nlisten = [\"127.0.0.1:{}\"]\nconnect = [{}]\n\n[custom]\nx = {}\n", section, section, port, connect, x).unwrap();
+}
+
+#[test]
+fn service_reload() {
+    init();
+    write_config(CONFIG, "reload_service", 44952, false, 1);
+    let mut config = load_config(CONFIG, None).unwrap();
+    let conf = ServiceConfig::from_toml(config.remove("reload_service").unwrap()).unwrap();
+    let service = TestService { custom : RefCell::new(None), conn : RefCell::new(0) };
+    service_start!(TEST_SERVICE, service, conf).unwrap();
+    TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().watch_section("reload_service"));
+    write_config(CONFIG, "reload_service", 44953, true, 2);
+    reload();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
+
+thread_local!(static CONNECTED : Cell<usize> = Cell::new(0));
+thread_local!(static RECONFIGURED : Cell<usize> = Cell::new(0));
+
+/// Reconnects to itself once a reload asks for it.
+struct WatchService;
+service_define!(WATCH_SERVICE : WatchService);
+
+impl ServiceHandler for WatchService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = WATCH_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            CONNECTED.with(|c| c.set(c.get() + 1));
+            // also stops the config poll
+            shutdown();
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn reconfigured(&self, _changed : &toml::Table) {
+        RECONFIGURED.with(|r| r.set(r.get() + 1));
+    }
+}
+
+fn start_watch(path : &str, section : &str, poll : Option<u64>) {
+    let mut config = load_config(path, poll).unwrap();
+    let conf = ServiceConfig::from_toml(config.remove(section).unwrap()).unwrap();
+    service_start!(WATCH_SERVICE, WatchService, conf).unwrap();
+    WATCH_SERVICE.with(|s| s.borrow().as_ref().unwrap().watch_section(section));
+}
+
+#[test]
+fn service_reload_sighup() {
+    let path = "/tmp/ds_service_reload_sighup.toml";
+    init();
+    write_config(path, "reload_sighup", 44963, false, 1);
+    // loading the config installs the SIGHUP handler, before that the signal would end the process
+    start_watch(path, "reload_sighup", None);
+    write_config(path, "reload_sighup", 44964, true, 2);
+    unsafe {
+        libc::raise(libc::SIGHUP);
+    }
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    assert_eq!(CONNECTED.with(|c| c.get()), 1);
+    assert_eq!(RECONFIGURED.with(|r| r.get()), 1);
+}
+
+#[test]
+fn service_reload_poll() {
+    let path = "/tmp/ds_service_reload_poll.toml";
+    init();
+    write_config(path, "reload_poll", 44965, false, 1);
+    start_watch(path, "reload_poll", Some(50));
+    // nothing is reloaded until the file changes
+    service_timer!(WATCH_SERVICE, 200, move |_ : &WatchService| {
+        assert_eq!(RECONFIGURED.with(|r| r.get()), 0);
+        write_config(path, "reload_poll", 44965, true, 2);
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    assert_eq!(CONNECTED.with(|c| c.get()), 1);
+    assert_eq!(RECONFIGURED.with(|r| r.get()), 1);
+}
+
+#[test]
+fn service_reload_exited() {
+    let path = "/tmp/ds_service_reload_exited.toml";
+    init();
+    write_config(path, "reload_exited", 44966, false, 1);
+    start_watch(path, "reload_exited", None);
+    service_exit!(WATCH_SERVICE);
+    write_config(path, "reload_exited", 44966, true, 2);
+    reload();
+    // the exited service neither connects nor hears about the change
+    assert_eq!(RECONFIGURED.with(|r| r.get()), 0);
+    run_loop();
+    assert_eq!(CONNECTED.with(|c| c.get()), 0);
+}
+
+#[test]
+fn service_reload_poll_again() {
+    let path = "/tmp/ds_service_reload_poll_again.toml";
+    init();
+    write_config(path, "reload_poll_again", 44972, false, 1);
+    load_config(path, Some(50)).unwrap();
+    // the second load takes over the poll of the first
+    start_watch(path, "reload_poll_again", Some(50));
+    service_timer!(WATCH_SERVICE, 200, move |_ : &WatchService| {
+        write_config(path, "reload_poll_again", 44972, true, 2);
+    });
+    trace!("loop begin");
+    // returns only if no poll timer was left behind
+    run_loop();
+    trace!("loop exit");
+    assert_eq!(CONNECTED.with(|c| c.get()), 1);
+    assert_eq!(RECONFIGURED.with(|r| r.get()), 1);
+}
@@ -0,0 +1,77 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+const ADDR : &'static str = "127.0.0.1:44951";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static LISTEN : Cell<Option<Token>> = Cell::new(None));
+
+struct TestService {
+    disc : RefCell<i32>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        assert_eq!(*self.disc.borrow(), 2);
+    }
+}
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            assert_eq!(service_remove_connect!(TEST_SERVICE, ADDR).unwrap(), 1);
+            let listen = LISTEN.with(|l| l.get()).unwrap();
+            assert!(service_unlisten!(TEST_SERVICE, listen));
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+        *self.disc.borrow_mut() += 1;
+        if *self.disc.borrow() == 2 {
+            // nothing reconnects and nothing listens any more
+            assert_eq!(TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().streams_count()), 0);
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_runtime() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_runtime".to_string(),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { disc : RefCell::new(0) }, conf).unwrap();
+    let listen = service_add_listen!(TEST_SERVICE, ADDR).unwrap();
+    LISTEN.with(|l| l.set(Some(listen)));
+    assert!(service_add_connect!(TEST_SERVICE, ADDR).unwrap().is_some());
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
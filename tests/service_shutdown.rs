@@ -0,0 +1,132 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate time;
+
+use std::cell::Cell;
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceStreamer, ServiceRef, ServiceConfig, WriteStatus};
+use ds::service::{init, run_loop, shutdown, set_shutdown_timeout};
+use ds::streamer::json::JsonStreamer;
+
+const PACKETS : usize = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    pad : Vec<u8>,
+}
+
+fn packet(last : bool) -> Packet {
+    Packet { pad : if last { vec![1] } else { vec![7;65536] } }
+}
+
+fn frame_len(last : bool) -> usize {
+    let mut frame = Vec::new();
+    JsonStreamer::<Packet>::write_packet(&packet(last), &mut frame).unwrap();
+    frame.len()
+}
+
+thread_local!(static PEER : Cell<Option<Token>> = Cell::new(None));
+thread_local!(static SHUTTING_DOWN : Cell<bool> = Cell::new(false));
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        PEER.with(|p| p.set(Some(token)));
+        // far more than the socket buffers take, most of it is still queued when the shutdown starts
+        for _ in 0..PACKETS {
+            assert_eq!(service_write!(TEST_SERVICE, token, &packet(false)), WriteStatus::Written);
+        }
+        shutdown();
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn shutting_down(&self) {
+        SHUTTING_DOWN.with(|s| s.set(true));
+        let token = PEER.with(|p| p.get()).unwrap();
+        assert_eq!(service_write!(TEST_SERVICE, token, &packet(true)), WriteStatus::Written);
+    }
+}
+
+#[test]
+fn service_shutdown_drain() {
+    init();
+    service_start!(TEST_SERVICE, TestService, ServiceConfig::server("service_shutdown", "127.0.0.1:44955")).unwrap();
+    let peer = thread::spawn(|| {
+        let mut stream = TcpStream::connect("127.0.0.1:44955").unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf.len()
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    assert!(SHUTTING_DOWN.with(|s| s.get()));
+    // every packet queued before the shutdown, and the one written from shutting_down, got through
+    assert_eq!(peer.join().unwrap(), frame_len(false) * PACKETS + frame_len(true));
+}
+
+struct StuckService;
+service_define!(STUCK_SERVICE : StuckService);
+
+impl ServiceHandler for StuckService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        for _ in 0..PACKETS {
+            service_write!(STUCK_SERVICE, token, &packet(false));
+        }
+        shutdown();
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_shutdown_deadline() {
+    init();
+    set_shutdown_timeout(200);
+    let mut conf = ServiceConfig::server("service_shutdown_deadline", "127.0.0.1:44956");
+    conf.linger = Some(60_000);
+    service_start!(STUCK_SERVICE, StuckService, conf).unwrap();
+    let (done, wait) = mpsc::channel();
+    let peer = thread::spawn(move || {
+        // never reads, so the server can not flush
+        let stream = TcpStream::connect("127.0.0.1:44956").unwrap();
+        wait.recv().unwrap();
+        drop(stream);
+    });
+    let begin = time::precise_time_ns();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    let elapsed = (time::precise_time_ns() - begin) / 1_000_000;
+    // the drain deadline cut the linger short
+    assert!(elapsed >= 200 && elapsed < 10_000, "elapsed {}", elapsed);
+    done.send(()).unwrap();
+    peer.join().unwrap();
+}
@@ -0,0 +1,69 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate libc;
+
+use std::cell::RefCell;
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+// SIGTERM shuts down every loop of the process, so this test has a binary of its own.
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static REASONS : RefCell<Vec<DisconnectReason>> = RefCell::new(Vec::new()));
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, reason : DisconnectReason) {
+        REASONS.with(|r| r.borrow_mut().push(reason));
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_signal_sigterm() {
+    init();
+    service_start!(TEST_SERVICE, TestService, ServiceConfig::server("service_signal", "127.0.0.1:44968")).unwrap();
+    let peer = thread::spawn(|| {
+        let mut stream = TcpStream::connect("127.0.0.1:44968").unwrap();
+        // caught on this thread, while the loop sits idle waiting for the peer
+        thread::sleep(Duration::from_millis(100));
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf.len()
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    // the shutdown drained the connection and closed it
+    assert_eq!(peer.join().unwrap(), 0);
+    assert_eq!(REASONS.with(|r| r.borrow().clone()), vec![DisconnectReason::Local]);
+}
@@ -0,0 +1,205 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init};
+use ds::service::sim;
+use ds::service::sim::SimConfig;
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    y : i32,
+    pad : Vec<u8>,
+}
+
+thread_local!(static LOG : RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+fn log(s : String) {
+    LOG.with(|l| l.borrow_mut().push(format!("{} {}", sim::now(), s)));
+}
+
+struct TestService {
+    pinging : RefCell<bool>,
+    cut : RefCell<bool>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        log(format!("conn {:?}", token));
+        if !*self.pinging.borrow() {
+            *self.pinging.borrow_mut() = true;
+            service_write!(TEST_SERVICE, token, &Packet{y:1, pad:vec![7;300]});
+        }
+    }
+    fn disconnected(&self, token : Token, _session : Self::Session, reason : DisconnectReason) {
+        log(format!("disc {:?} {:?}", token, reason));
+        *self.pinging.borrow_mut() = false;
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        log(format!("recv {:?} {}", token, packet.y));
+        assert_eq!(packet.pad.len(), 300);
+        if packet.y == 5 && !*self.cut.borrow() {
+            *self.cut.borrow_mut() = true;
+            sim::disconnect(token);
+        } else if packet.y < 10 {
+            service_write!(TEST_SERVICE, token, &Packet{y:packet.y+1, pad:packet.pad});
+        } else {
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+fn run(seed : u64) -> (u64, Vec<String>) {
+    thread::spawn(move || {
+        init();
+        let mut config = SimConfig::new(seed);
+        config.latency_max = 50;
+        config.split = 0.5;
+        sim::start(config);
+        // no real socket is bound, the port can be shared with the other tests
+        let conf = ServiceConfig {
+            name : "service_sim".to_string(),
+            listen : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+            connect : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        service_start!(TEST_SERVICE, TestService { pinging : RefCell::new(false), cut : RefCell::new(false) }, conf).unwrap();
+        let end = sim::run();
+        (end, LOG.with(|l| l.borrow().clone()))
+    }).join().unwrap()
+}
+
+#[test]
+fn service_sim() {
+    let (end, log) = run(7);
+    trace!("sim end {} {:?}", end, log);
+    // the forced disconnect is followed by a reconnect after the 5s retry delay
+    assert!(end > 5_000);
+    assert_eq!(log.iter().filter(|l| l.contains("conn")).count(), 4);
+    assert_eq!(log.iter().filter(|l| l.contains("recv")).count(), 15);
+    assert_eq!(run(7), (end, log));
+}
+
+thread_local!(static GOT : Cell<bool> = Cell::new(false));
+
+struct EarlyService;
+service_define!(EARLY_SERVICE : EarlyService);
+
+impl ServiceHandler for EarlyService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = EARLY_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if !info.is_client {
+            // often on its way before the client learns that it is connected
+            service_write!(EARLY_SERVICE, token, &Packet{y:1, pad:vec![]});
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        assert_eq!(packet.y, 1);
+        GOT.with(|g| g.set(true));
+        service_exit!(EARLY_SERVICE);
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_sim_early_data() {
+    for seed in 1..20 {
+        let got = thread::spawn(move || {
+            init();
+            let mut config = SimConfig::new(seed);
+            config.latency_max = 50;
+            sim::start(config);
+            let conf = ServiceConfig {
+                name : "service_sim_early_data".to_string(),
+                listen : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+                connect : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            };
+            service_start!(EARLY_SERVICE, EarlyService, conf).unwrap();
+            sim::run();
+            GOT.with(|g| g.get())
+        }).join().unwrap();
+        assert!(got, "seed {} lost the packet", seed);
+    }
+}
+
+thread_local!(static SESSIONS : RefCell<Vec<u32>> = RefCell::new(Vec::new()));
+
+struct SessionService;
+service_define!(SESSION_SERVICE : SessionService);
+
+impl ServiceHandler for SessionService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    /// packets received on the connection
+    type Session = u32;
+    fn connected(&self, token : Token) -> u32 {
+        let info = SESSION_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            for y in 0..4 {
+                service_write!(SESSION_SERVICE, token, &Packet{y:y, pad:vec![]});
+            }
+        }
+        0
+    }
+    fn disconnected(&self, _token : Token, session : u32, _reason : DisconnectReason) {
+        let n = SESSIONS.with(|s| {
+            s.borrow_mut().push(session);
+            s.borrow().len()
+        });
+        if n == 2 {
+            service_exit!(SESSION_SERVICE);
+        }
+    }
+    fn incoming(&self, token : Token, session : &mut u32, packet : Self::Packet) {
+        *session += 1;
+        if packet.y == 3 {
+            service_shutdown!(SESSION_SERVICE, token);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_sim_session() {
+    let mut sessions = thread::spawn(|| {
+        init();
+        sim::start(SimConfig::new(9));
+        let conf = ServiceConfig {
+            name : "service_sim_session".to_string(),
+            listen : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+            connect : vec!["0.0.0.0:44944"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        service_start!(SESSION_SERVICE, SessionService, conf).unwrap();
+        sim::run();
+        SESSIONS.with(|s| s.borrow().clone())
+    }).join().unwrap();
+    sessions.sort();
+    // each end gets its own session back, the client received nothing
+    assert_eq!(sessions, vec![0, 4]);
+}
@@ -0,0 +1,89 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ErrorKind, ServiceHandler, ServiceStreamer, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+thread_local!(static SKIPPED : RefCell<Vec<u32>> = RefCell::new(Vec::new()));
+thread_local!(static INCOMING : RefCell<Vec<i32>> = RefCell::new(Vec::new()));
+thread_local!(static CLOSED : RefCell<Option<(u32, DisconnectReason)>> = RefCell::new(None));
+thread_local!(static ERRORS : RefCell<Vec<ErrorKind>> = RefCell::new(Vec::new()));
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    /// packets received
+    type Session = u32;
+    fn connected(&self, _token : Token) -> u32 {
+        0
+    }
+    fn disconnected(&self, _token : Token, session : Self::Session, reason : DisconnectReason) {
+        CLOSED.with(|c| *c.borrow_mut() = Some((session, reason)));
+        service_exit!(TEST_SERVICE);
+    }
+    fn incoming(&self, _token : Token, session : &mut Self::Session, packet : Self::Packet) {
+        *session += 1;
+        INCOMING.with(|i| i.borrow_mut().push(packet.x));
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn skipped(&self, _token : Token, skips : u32) {
+        SKIPPED.with(|s| s.borrow_mut().push(skips));
+    }
+    fn on_error(&self, _token : Token, kind : ErrorKind) {
+        ERRORS.with(|e| e.borrow_mut().push(kind));
+    }
+}
+
+#[test]
+fn service_skip() {
+    init();
+    let mut conf = ServiceConfig::server("service_skip", "127.0.0.1:44960");
+    conf.max_skips = Some(2);
+    service_start!(TEST_SERVICE, TestService, conf).unwrap();
+    let client = thread::spawn(|| {
+        // a frame whose length is right but whose body is not json
+        let bad = b"[3]abc";
+        let mut frames = Vec::new();
+        frames.extend(bad.iter().cloned());
+        JsonStreamer::<Packet>::write_packet(&Packet{x:1}, &mut frames).unwrap();
+        frames.extend(bad.iter().cloned());
+        JsonStreamer::<Packet>::write_packet(&Packet{x:2}, &mut frames).unwrap();
+        frames.extend(bad.iter().cloned());
+        let mut stream = TcpStream::connect("127.0.0.1:44960").unwrap();
+        stream.write_all(&frames).unwrap();
+        // the server closes after the third bad frame, without a word
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).ok();
+        rest.len()
+    });
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    assert_eq!(client.join().unwrap(), 0);
+    // the good frames between the bad ones got through
+    assert_eq!(SKIPPED.with(|s| s.borrow().clone()), vec![1, 2]);
+    assert_eq!(INCOMING.with(|i| i.borrow().clone()), vec![1, 2]);
+    assert_eq!(CLOSED.with(|c| *c.borrow()), Some((2, DisconnectReason::Error(ErrorKind::Decode))));
+    assert_eq!(ERRORS.with(|e| e.borrow().clone()), vec![ErrorKind::Decode]);
+}
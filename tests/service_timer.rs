@@ -0,0 +1,94 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct Stat {
+    sent : i32,
+    recv : i32,
+    disc : i32,
+    once : i32,
+}
+
+impl Drop for Stat {
+    fn drop(&mut self) {
+        assert_eq!(self.sent, 3);
+        assert_eq!(self.recv, 3);
+        assert_eq!(self.disc, 2);
+        assert_eq!(self.once, 1);
+    }
+}
+
+struct TestService {
+    stat : RefCell<Stat>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        if self.stat.borrow().sent > 0 {
+            return;
+        }
+        self.stat.borrow_mut().sent = 1;
+        // a heartbeat tied to the connection, it stops by itself once the peer closes
+        service_timer!(TEST_SERVICE, 50, true, Some(token), move |h : &TestService| {
+            let x = h.stat.borrow().sent;
+            if x < 3 {
+                h.stat.borrow_mut().sent += 1;
+            }
+            service_write!(TEST_SERVICE, token, &Packet{x:x});
+        });
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+        self.stat.borrow_mut().disc += 1;
+        if self.stat.borrow().disc > 1 {
+            return;
+        }
+        service_timer!(TEST_SERVICE, 50, |h : &TestService| {
+            h.stat.borrow_mut().once += 1;
+            service_exit!(TEST_SERVICE);
+        });
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        self.stat.borrow_mut().recv += 1;
+        if packet.x == 3 {
+            service_shutdown!(TEST_SERVICE, token);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_timer() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_timer".to_string(),
+        listen : vec!["0.0.0.0:44946"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44946"].iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { stat : RefCell::new(Stat { sent : 0, recv : 0, disc : 0, once : 0 }) }, conf).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
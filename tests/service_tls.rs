@@ -0,0 +1,153 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, TlsConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct Server;
+service_define!(SERVER : Server);
+
+impl ServiceHandler for Server {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        service_write!(SERVER, token, &Packet{x:packet.x+1});
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+struct Client {
+    recv : RefCell<i32>,
+}
+service_define!(CLIENT : Client);
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        assert_eq!(*self.recv.borrow(), 1);
+    }
+}
+
+impl ServiceHandler for Client {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        service_write!(CLIENT, token, &Packet{x:1});
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        assert_eq!(packet.x, 2);
+        *self.recv.borrow_mut() += 1;
+        service_exit!(CLIENT);
+        service_exit!(SERVER);
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+fn tls(name : &str) -> TlsConfig {
+    TlsConfig {
+        cert : Some(format!("tests/tls/{}.pem", name)),
+        key : Some(format!("tests/tls/{}.key", name)),
+        ca : Some("tests/tls/ca.pem".to_string()),
+        verify_peer : Some(true),
+        insecure : None,
+    }
+}
+
+#[test]
+fn service_tls() {
+    init();
+    let mut server = ServiceConfig::server("tls_server", "tls:127.0.0.1:44950");
+    server.tls = Some(tls("server"));
+    let mut client = ServiceConfig::client("tls_client", "tls:127.0.0.1:44950");
+    client.tls = Some(tls("client"));
+    service_start!(SERVER, Server, server).unwrap();
+    service_start!(CLIENT, Client { recv : RefCell::new(0) }, client).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
+
+thread_local!(static MISMATCH : RefCell<Option<DisconnectReason>> = RefCell::new(None));
+
+struct MismatchServer;
+service_define!(MISMATCH_SERVER : MismatchServer);
+
+impl ServiceHandler for MismatchServer {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, _token : Token) {
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+        panic!("the client should have refused the certificate");
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+struct MismatchClient;
+service_define!(MISMATCH_CLIENT : MismatchClient);
+
+impl ServiceHandler for MismatchClient {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        service_write!(MISMATCH_CLIENT, token, &Packet{x:1});
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, reason : DisconnectReason) {
+        MISMATCH.with(|m| *m.borrow_mut() = Some(reason));
+        service_exit!(MISMATCH_CLIENT);
+        service_exit!(MISMATCH_SERVER);
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+        panic!("the handshake should have failed");
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_tls_hostname_mismatch() {
+    init();
+    // the server certificate is for localhost and 127.0.0.1 only
+    let mut server = ServiceConfig::server("tls_mismatch_server", "tls:127.0.0.2:44962");
+    server.tls = Some(tls("server"));
+    let mut client = ServiceConfig::client("tls_mismatch_client", "tls:127.0.0.2:44962");
+    client.tls = Some(tls("client"));
+    service_start!(MISMATCH_SERVER, MismatchServer, server).unwrap();
+    service_start!(MISMATCH_CLIENT, MismatchClient, client).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    match MISMATCH.with(|m| *m.borrow()) {
+        Some(DisconnectReason::Error(_)) => {}
+        reason => panic!("unexpected {:?}", reason),
+    }
+}
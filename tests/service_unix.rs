@@ -0,0 +1,77 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, Addr, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+const SOCK : &'static str = "/tmp/ds_service_unix.sock";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct TestService {
+    recv : RefCell<i32>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        assert_eq!(*self.recv.borrow(), 2);
+    }
+}
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        assert_eq!(info.peer_addr, Addr::Unix(PathBuf::from(SOCK)));
+        if info.is_client {
+            service_write!(TEST_SERVICE, token, &Packet{x:1});
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, token : Token, _session : &mut Self::Session, packet : Self::Packet) {
+        *self.recv.borrow_mut() += 1;
+        if packet.x == 1 {
+            service_write!(TEST_SERVICE, token, &Packet{x:2});
+        } else {
+            service_exit!(TEST_SERVICE);
+        }
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_unix() {
+    init();
+    let addr = format!("unix:{}", SOCK);
+    let conf = ServiceConfig {
+        name : "service_unix".to_string(),
+        listen : vec![addr.clone()],
+        connect : vec![addr],
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { recv : RefCell::new(0) }, conf).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+    // the listener took its socket file with it
+    assert!(!Path::new(SOCK).exists());
+}
@@ -0,0 +1,92 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::io::Write;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, WatermarkConfig, WriteStatus, init, run_loop};
+use ds::streamer::json::JsonStreamer;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    pad : Vec<u8>,
+}
+
+struct Stat {
+    written : i32,
+    blocked : i32,
+    drained : i32,
+}
+
+impl Drop for Stat {
+    fn drop(&mut self) {
+        assert!(self.written > 0);
+        assert_eq!(self.blocked, 1);
+        assert_eq!(self.drained, 1);
+    }
+}
+
+struct TestService {
+    stat : RefCell<Stat>,
+}
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        let info = TEST_SERVICE.with(|s| s.borrow().as_ref().unwrap().connection_info(token)).unwrap();
+        if info.is_client {
+            return;
+        }
+        // the client end does not read until the loop gets back to it, so the kernel buffers fill up first
+        for _ in 0..1000 {
+            match service_write!(TEST_SERVICE, token, &Packet{pad:vec![7;65536]}) {
+                WriteStatus::Written => {
+                    self.stat.borrow_mut().written += 1;
+                }
+                WriteStatus::WouldBlock => {
+                    self.stat.borrow_mut().blocked += 1;
+                    break;
+                }
+                status => {
+                    panic!("unexpected {:?}", status);
+                }
+            }
+        }
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+    fn on_drain(&self, _token : Token) {
+        self.stat.borrow_mut().drained += 1;
+        service_exit!(TEST_SERVICE);
+    }
+}
+
+#[test]
+fn service_watermark() {
+    init();
+    let conf = ServiceConfig {
+        name : "service_watermark".to_string(),
+        listen : vec!["0.0.0.0:44947"].iter().map(|s| s.to_string()).collect(),
+        connect : vec!["127.0.0.1:44947"].iter().map(|s| s.to_string()).collect(),
+        watermark : Some(WatermarkConfig { high : 1 << 20, low : 1 << 16, overflow : "block".to_string() }),
+        ..Default::default()
+    };
+    service_start!(TEST_SERVICE, TestService { stat : RefCell::new(Stat { written : 0, blocked : 0, drained : 0 }) }, conf).unwrap();
+    trace!("loop begin");
+    run_loop();
+    trace!("loop exit");
+}
@@ -0,0 +1,82 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+
+#[macro_use]
+extern crate ds;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+use serde::{Serializer, Deserializer};
+
+use ds::service::{Token, DisconnectReason, ServiceHandler, ServiceRef, ServiceConfig, LoopSender, run_workers, worker_id, sender, shutdown};
+use ds::streamer::json::JsonStreamer;
+
+const WORKERS : usize = 3;
+const CLIENTS : usize = 12;
+
+static SERVED : AtomicUsize = ATOMIC_USIZE_INIT;
+static STARTED : AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    x : i32,
+}
+
+struct TestService;
+service_define!(TEST_SERVICE : TestService);
+
+impl ServiceHandler for TestService {
+    type Packet = Packet;
+    type Streamer = JsonStreamer<Packet>;
+    type Session = ();
+    fn connected(&self, token : Token) {
+        SERVED.fetch_add(1, Ordering::SeqCst);
+        service_close_after_flush!(TEST_SERVICE, token);
+    }
+    fn disconnected(&self, _token : Token, _session : Self::Session, _reason : DisconnectReason) {
+    }
+    fn incoming(&self, _token : Token, _session : &mut Self::Session, _packet : Self::Packet) {
+    }
+    fn outgoing(&self, _token : Token, _session : Option<&mut Self::Session>, _packet : &Self::Packet) {
+    }
+}
+
+#[test]
+fn service_workers() {
+    let senders : Arc<Mutex<Vec<LoopSender>>> = Arc::new(Mutex::new(Vec::new()));
+    let clients = {
+        let senders = senders.clone();
+        thread::spawn(move || {
+            // every worker has bound before the first connect
+            while senders.lock().unwrap().len() < WORKERS {
+                thread::sleep(Duration::from_millis(10));
+            }
+            for _ in 0..CLIENTS {
+                let mut stream = TcpStream::connect("127.0.0.1:44969").unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).unwrap();
+            }
+            for sender in senders.lock().unwrap().iter() {
+                sender.post(|| shutdown()).unwrap();
+            }
+        })
+    };
+    run_workers(WORKERS, move |id| {
+        assert_eq!(worker_id(), Some(id));
+        // the same address on every worker
+        service_start!(TEST_SERVICE, TestService, ServiceConfig::server("service_workers", "127.0.0.1:44969")).unwrap();
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        senders.lock().unwrap().push(sender());
+    });
+    // returned, so every worker loop exited
+    clients.join().unwrap();
+    assert_eq!(STARTED.load(Ordering::SeqCst), WORKERS);
+    assert_eq!(SERVED.load(Ordering::SeqCst), CLIENTS);
+}